@@ -0,0 +1,276 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+const TEMP_MARKER: &str = ".mack-tmp-";
+
+/// Returns a hidden path alongside `path` to stage writes in before renaming them into place.
+/// Being in the same directory means that the final rename can't cross devices.
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}{TEMP_MARKER}{}", std::process::id()))
+}
+
+/// Whether `path` is a temporary file from `temp_path_for` which was left behind by another run,
+/// presumably because it was killed. This only goes by the name, so it's only safe to remove
+/// these while holding the lock on their directory.
+pub fn is_stale_temp(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some((_, pid)) = name.rsplit_once(TEMP_MARKER) else {
+        return false;
+    };
+    name.starts_with('.')
+        && !pid.is_empty()
+        && pid.bytes().all(|b| b.is_ascii_digit())
+        && pid != std::process::id().to_string()
+}
+
+/// Returns the path which a temporary file from `temp_path_for` was standing in for.
+pub fn temp_target(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let (name, _) = name.strip_prefix('.')?.rsplit_once(TEMP_MARKER)?;
+    Some(path.with_file_name(name))
+}
+
+/// Makes sure that a rename into `path`'s directory has hit the disk.
+pub fn sync_parent(path: &Path) -> Result<()> {
+    #[cfg(target_family = "unix")]
+    fs::File::open(path.parent().context("No parent directory to sync")?)?.sync_all()?;
+    // Windows has no way to open a directory for syncing, and NTFS journals renames anyway.
+    #[cfg(target_family = "windows")]
+    let _ = path;
+    Ok(())
+}
+
+/// Copies the modification and access times from `from` onto `to`.
+pub fn copy_times(from: &fs::Metadata, to: &Path) -> Result<()> {
+    let times = fs::FileTimes::new()
+        .set_modified(from.modified()?)
+        .set_accessed(from.accessed()?);
+    fs::File::options().write(true).open(to)?.set_times(times)?;
+    Ok(())
+}
+
+/// Copies extended attributes from `from` onto `to`. This is best effort, since many of the
+/// filesystems we move music to (like FAT) don't support them at all.
+#[cfg(target_family = "unix")]
+fn copy_xattrs(from: &Path, to: &Path) {
+    let Ok(names) = xattr::list(from) else {
+        return;
+    };
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(from, &name) {
+            let _ = xattr::set(to, &name, &value);
+        }
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn copy_xattrs(_from: &Path, _to: &Path) {}
+
+/// Gives `to` the owner and group from `from`, returning false if we aren't allowed to. Changing
+/// the owner can clear the setuid and setgid bits, so the permissions are restored afterwards.
+#[cfg(target_family = "unix")]
+fn copy_ownership(from: &fs::Metadata, to: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let to_meta = fs::metadata(to)?;
+    if (to_meta.uid(), to_meta.gid()) == (from.uid(), from.gid()) {
+        return Ok(true);
+    }
+    if std::os::unix::fs::chown(to, Some(from.uid()), Some(from.gid())).is_err() {
+        return Ok(false);
+    }
+    fs::set_permissions(to, from.permissions())?;
+    Ok(true)
+}
+
+/// New files inherit their ACL from the directory on Windows, so there's nothing to copy.
+#[cfg(target_family = "windows")]
+fn copy_ownership(_from: &fs::Metadata, _to: &Path) -> Result<bool> {
+    Ok(true)
+}
+
+/// Whether the file has other hard links, which replacing it would detach from it.
+#[cfg(target_family = "unix")]
+fn has_other_links(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink() > 1
+}
+
+#[cfg(target_family = "windows")]
+fn has_other_links(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Checks that two files have identical contents.
+fn verify_same_contents(a: &Path, b: &Path) -> Result<()> {
+    let (a_len, b_len) = (fs::metadata(a)?.len(), fs::metadata(b)?.len());
+    if a_len != b_len {
+        bail!("size mismatch after copy ({a_len} != {b_len})");
+    }
+
+    let mut a = BufReader::new(fs::File::open(a)?);
+    let mut b = BufReader::new(fs::File::open(b)?);
+    let mut a_buf = vec![0; 64 * 1024];
+    let mut b_buf = vec![0; 64 * 1024];
+    loop {
+        let n = a.read(&mut a_buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        b.read_exact(&mut b_buf[..n])?;
+        if a_buf[..n] != b_buf[..n] {
+            bail!("content mismatch after copy");
+        }
+    }
+}
+
+/// Copies `from` into a temporary file next to `to`, preserving permissions and extended
+/// attributes, and returns the temporary path.
+fn stage_copy(from: &Path, to: &Path) -> Result<PathBuf> {
+    let tmp = temp_path_for(to);
+    // Also copies permissions
+    if let Err(err) = fs::copy(from, &tmp) {
+        let _ = fs::remove_file(&tmp);
+        Err(err)?;
+    }
+    copy_xattrs(from, &tmp);
+    Ok(tmp)
+}
+
+/// Syncs `tmp` and renames it over `to`, cleaning up `tmp` if anything along the way (including
+/// `prepare`) fails.
+fn commit(tmp: &Path, to: &Path, prepare: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let res = (|| -> Result<()> {
+        prepare(tmp)?;
+        fs::File::open(tmp)?.sync_all()?;
+        fs::rename(tmp, to)?;
+        sync_parent(to)
+    })();
+
+    if res.is_err() {
+        let _ = fs::remove_file(tmp);
+    }
+    res
+}
+
+/// Copies `from` to `to` such that `to` either doesn't exist or is a complete, verified copy,
+/// even if we crash or run out of space midway. Permissions, timestamps and extended attributes
+/// are preserved, as is ownership if we're allowed to change it.
+pub fn copy_verified(from: &Path, to: &Path) -> Result<()> {
+    let meta = fs::metadata(from)?;
+    let tmp = stage_copy(from, to)?;
+    commit(&tmp, to, |tmp| {
+        // Like mv, moving someone else's file to another device makes it ours if it has to.
+        copy_ownership(&meta, tmp)?;
+        copy_times(&meta, tmp)?;
+        verify_same_contents(from, tmp)
+    })
+}
+
+/// Replaces `path` with a copy of itself which has been changed by `modify`, such that `path` is
+/// always either entirely the old or entirely the new version, even if we crash midway.
+///
+/// Files with other hard links, or which we can't give the same owner, are refused, since replacing
+/// them would detach the links or change the owner, and changing them in place isn't crash safe.
+pub fn modify_via_temp(path: &Path, modify: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let meta = fs::metadata(path)?;
+    if has_other_links(&meta) {
+        bail!("not rewriting file with other hard links, since that would detach them");
+    }
+    let tmp = stage_copy(path, path)?;
+    match copy_ownership(&meta, &tmp) {
+        Ok(true) => commit(&tmp, path, modify),
+        Ok(false) => {
+            fs::remove_file(&tmp)?;
+            bail!("not rewriting file, since we can't give the new copy the same owner")
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            Err(err)
+        }
+    }
+}
+
+/// Writes `data` to `path` such that `path` is always either absent (or its old contents), or
+/// complete.
+pub fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    commit(&temp_path_for(path), path, |tmp| Ok(fs::write(tmp, data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_verified() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let from = dir.join("from.mp3");
+        let to = dir.join("to.mp3");
+        fs::write(&from, b"not really an mp3").unwrap();
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        copy_verified(&from, &to).unwrap();
+
+        assert_eq!(fs::read(&to).unwrap(), b"not really an mp3");
+        assert_eq!(fs::metadata(&to).unwrap().modified().unwrap(), old);
+        assert!(!temp_path_for(&to).exists());
+    }
+
+    #[test]
+    fn test_modify_via_temp_failure_leaves_original() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("file.mp3");
+        fs::write(&path, b"original").unwrap();
+
+        let res = modify_via_temp(&path, |tmp| {
+            fs::write(tmp, b"half writ")?;
+            bail!("interrupted")
+        });
+
+        assert!(res.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert!(!temp_path_for(&path).exists());
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_modify_via_temp_refuses_hard_links() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("file.mp3");
+        let link = dir.join("link.mp3");
+        fs::write(&path, b"original").unwrap();
+        fs::hard_link(&path, &link).unwrap();
+
+        assert!(modify_via_temp(&path, |p| Ok(fs::write(p, b"modified")?)).is_err());
+
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert_eq!(fs::read(&link).unwrap(), b"original");
+        assert!(!temp_path_for(&path).exists());
+    }
+
+    #[test]
+    fn test_is_stale_temp() {
+        assert!(is_stale_temp(Path::new("a/.01 Foo.mp3.mack-tmp-1")));
+        assert!(!is_stale_temp(&temp_path_for(Path::new("a/01 Foo.mp3"))));
+        assert!(!is_stale_temp(Path::new("a/01 Foo.mp3.mack-tmp-1")));
+        assert!(!is_stale_temp(Path::new("a/.01 Foo.mp3.mack-tmp-")));
+    }
+
+    #[test]
+    fn test_temp_target() {
+        let path = Path::new("a/The Beatles");
+        assert_eq!(temp_target(&temp_path_for(path)).as_deref(), Some(path));
+        assert_eq!(temp_target(Path::new("a/The Beatles")), None);
+    }
+}
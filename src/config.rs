@@ -1,4 +1,10 @@
-use clap::Parser;
+use crate::track::feat::{FeatBrackets, FeatKeyword, DEFAULT_FEAT_KEYWORDS};
+use crate::track::profile::{FsProfile, LenUnit};
+use crate::track::qualifier::QualifierPlacement;
+use crate::track::rename::DEFAULT_MAX_PART_LEN;
+use crate::track::stray::StrayTagMode;
+use crate::track::version::Id3Version;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -25,6 +31,177 @@ pub struct Config {
     )]
     pub output_dir: Option<PathBuf>,
 
+    /// Keywords which introduce featured artists, like "feat" in "Title (feat. Artist)".
+    ///
+    /// Each keyword can be followed by ":word" (the default) to match it anywhere that it isn't
+    /// part of a larger word, ":space" to only match it surrounded by whitespace (for keywords
+    /// like "x"), or ":bracket" to only match it at the start of a bracketed section (for
+    /// keywords like "with", which otherwise appear in plenty of ordinary titles).
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_FEAT_KEYWORDS)]
+    pub feat_keywords: Vec<FeatKeyword>,
+
+    /// A file listing artist names which contain "&", "and", or commas, one per line.
+    ///
+    /// These are never split into separate featured artists, so that for example "Simon &
+    /// Garfunkel" stays intact.
+    #[arg(long)]
+    pub protected_artists: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Also protect a built-in list of well known artist names from being split"
+    )]
+    pub builtin_protected_artists: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = FeatBrackets::Round,
+        help = "The brackets to put around featured artists in titles"
+    )]
+    pub feat_brackets: FeatBrackets,
+
+    #[arg(
+        long,
+        default_value = "feat.",
+        help = "The keyword to introduce featured artists with in titles"
+    )]
+    pub feat_keyword: String,
+
+    #[arg(
+        long,
+        default_value = ", ",
+        help = "The separator between featured artists in titles"
+    )]
+    pub feat_separator: String,
+
+    #[arg(
+        long,
+        default_value = "and",
+        help = "The word joining the last two featured artists in titles, like \"and\" or \"&\""
+    )]
+    pub feat_conjunction: String,
+
+    #[arg(
+        long,
+        help = "Don't put a separator before the conjunction when there are 3 or more featured artists"
+    )]
+    pub no_oxford_comma: bool,
+
+    /// Where to put version qualifiers like "Radio Edit" or "2011 Remaster".
+    ///
+    /// These are always moved to a bracketed suffix after any featured artists. They can also be
+    /// written to the subtitle (TIT3) frame, which is available as {subtitle} in --fmt.
+    #[arg(long, value_enum, default_value_t = QualifierPlacement::Title)]
+    pub qualifiers: QualifierPlacement,
+
+    /// A file mapping genres to their canonical names, one "from = to" pair per line.
+    ///
+    /// Genres are matched ignoring case, spaces, and punctuation, so "hiphop = Hip-Hop" also
+    /// covers "Hip Hop" and "hip-hop". Values are only split into several genres on ";", so
+    /// mapping to something like "Hip-Hop; Rap" splits a genre up, and mapping to nothing drops it.
+    #[arg(long)]
+    pub genre_map: Option<PathBuf>,
+
+    /// Leave dates as they are, rather than rewriting them in a canonical form.
+    ///
+    /// Dates are still converted between the TDRC and TYER/TDAT/TIME frames when --id3-version
+    /// changes a file's version, since only one set of them is valid in each.
+    #[arg(long)]
+    pub no_fix_dates: bool,
+
+    /// Change dates and genres which disagree with the rest of their album to match it.
+    ///
+    /// By default, these are only reported, since tracks on an album can legitimately differ.
+    /// Tracks without a date or genre are also given the album's. Compilations are never changed.
+    #[arg(long)]
+    pub fix_album_dates_genres: bool,
+
+    /// The ID3v2 version to write tags as.
+    ///
+    /// Files already using a different version are converted, even if nothing else about them
+    /// needs fixing. By default, files keep the version they already have.
+    #[arg(long, value_enum)]
+    pub id3_version: Option<Id3Version>,
+
+    /// What to do with ID3v1, APEv2, and duplicate ID3v2 tags, which players often show instead
+    /// of the (fixed) main tag.
+    #[arg(long, value_enum, default_value_t = StrayTagMode::Keep)]
+    pub stray_tags: StrayTagMode,
+
+    /// Keep files' original modification and access times when rewriting their tags.
+    ///
+    /// Renames always keep them. Since this leaves mtimes from before the last run, runs with this
+    /// set also check the inode change time to decide which files need looking at, so files moved
+    /// into place with an old mtime are picked up too.
+    #[arg(long)]
+    pub preserve_times: bool,
+
+    #[arg(
+        long,
+        help = "Save each file's original tag under .mack/backups in the output dir before changing it"
+    )]
+    pub backup: bool,
+
+    #[arg(
+        long,
+        help = "If another mack instance is working on the same directories, wait for it instead of failing"
+    )]
+    pub wait: bool,
+
+    /// The filesystem that output paths must be valid on.
+    ///
+    /// This controls which characters are replaced, reserved names like "CON", the maximum length
+    /// of path components and whole paths, and whether paths differing only by case are
+    /// considered to collide. The default only replaces the characters Windows rejects, as mack
+    /// always has, so that existing libraries aren't renamed.
+    #[arg(long, value_enum, default_value_t = FsProfile::Legacy)]
+    pub fs_profile: FsProfile,
+
+    #[arg(
+        long,
+        help = "Romanise non-ASCII characters in output paths (tags are left untouched)"
+    )]
+    pub transliterate: bool,
+
+    /// The maximum length of each output path component, not counting the extension.
+    ///
+    /// This is applied in addition to the limits of --fs-profile. Truncated file names have a short
+    /// hash of the full name appended, so that files sharing a long prefix don't collide.
+    #[arg(long, default_value_t = DEFAULT_MAX_PART_LEN)]
+    pub max_part_len: usize,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = LenUnit::Chars,
+        help = "How --max-part-len is measured"
+    )]
+    pub part_len_unit: LenUnit,
+
+    #[arg(long, help = "Count the file extension towards --max-part-len")]
+    pub part_len_includes_ext: bool,
+
+    #[arg(
+        long,
+        help = "Cut path components which are too long at a word boundary where possible"
+    )]
+    pub truncate_at_word: bool,
+
+    #[arg(
+        long,
+        help = "After renaming, remove source directories which were left empty by mack's moves"
+    )]
+    pub prune_empty_dirs: bool,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "Thumbs.db,desktop.ini,.DS_Store",
+        help = "Files which don't stop a directory from being considered empty when pruning"
+    )]
+    pub prune_junk: Vec<String>,
+
     /// The format to apply to files, excluding the extension.
     ///
     /// Substitutions can be applied inside curly brackets, for example with {artist} to get the
@@ -35,9 +212,14 @@ pub struct Config {
     /// TAG:
     ///
     ///   artist
+    ///   albumartist  (falls back to artist)
     ///   album
     ///   track  (width: 2)
+    ///   disc  (empty if there isn't one)
     ///   title
+    ///   subtitle  (empty if there isn't one)
+    ///   year  (empty if unknown)
+    ///   originalyear  (falls back to year)
     ///
     /// LITERAL:
     ///
@@ -51,4 +233,18 @@ pub struct Config {
 
     #[arg(help = "Directories to find music files in.")]
     pub paths: Option<Vec<PathBuf>>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Restore files' tags to how they were before mack first changed them.
+    ///
+    /// This only works for files which were changed while --backup was in use.
+    Restore {
+        #[arg(required = true, help = "Files to restore.")]
+        paths: Vec<PathBuf>,
+    },
 }
@@ -0,0 +1,31 @@
+use std::hash::Hasher;
+
+/// FNV-1a, used over `DefaultHasher` anywhere a hash ends up on disk, since its output must be
+/// stable across runs, platforms, and Rust versions.
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A short hash of `bytes`, for use in file names.
+pub fn short_hash(bytes: &[u8]) -> String {
+    let mut hasher = Fnv1a::default();
+    hasher.write(bytes);
+    let hash = hasher.finish();
+    format!("{:08x}", (hash ^ (hash >> 32)) as u32)
+}
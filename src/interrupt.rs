@@ -0,0 +1,20 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a handler for SIGINT/SIGTERM (or their Windows equivalents) which asks the run to stop
+/// once in-flight files are done. A second signal exits immediately.
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("interrupted, finishing in-flight files (interrupt again to exit immediately)");
+    })?;
+    Ok(())
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
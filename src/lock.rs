@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const LOCK_NAME: &str = ".macklock";
+
+fn make_lock_path(base_path: &Path) -> PathBuf {
+    let mut lock_path = base_path.to_path_buf();
+    lock_path.push(LOCK_NAME);
+    lock_path
+}
+
+/// An advisory lock on a directory, held until this is dropped.
+pub struct RunLock {
+    _file: fs::File,
+}
+
+#[cfg(target_family = "unix")]
+fn try_lock(file: &fs::File, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let flags = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    // SAFETY: The fd is valid for as long as `file` is borrowed.
+    if unsafe { libc::flock(file.as_raw_fd(), flags) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(target_family = "windows")]
+fn try_lock(file: &fs::File, wait: bool) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+    if !wait {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    // SAFETY: The handle is valid for as long as `file` is borrowed, and OVERLAPPED is plain data.
+    let ok = unsafe {
+        let mut overlapped: OVERLAPPED = std::mem::zeroed();
+        LockFileEx(
+            file.as_raw_handle().cast(),
+            flags,
+            0,
+            !0,
+            !0,
+            &mut overlapped,
+        )
+    };
+    if ok != 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Locks `dir` so that no other mack instance can work on it at the same time. If another
+/// instance holds the lock, either wait for it or fail, depending on `wait`.
+fn lock(dir: &Path, wait: bool) -> Result<RunLock> {
+    let lock_path = make_lock_path(dir);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("can't open {}", lock_path.display()))?;
+
+    if !try_lock(&file, false)? {
+        if !wait {
+            bail!(
+                "another mack instance is already running on {} (use --wait to wait for it)",
+                dir.display()
+            );
+        }
+        eprintln!(
+            "{}: waiting for another mack instance to finish",
+            dir.display()
+        );
+        try_lock(&file, true)?;
+    }
+
+    Ok(RunLock { _file: file })
+}
+
+/// Locks all of `dirs`. They're always taken in the same order, so two instances waiting on each
+/// other's input and output directories can't deadlock.
+pub fn lock_all(dirs: &[&Path], wait: bool) -> Result<Vec<RunLock>> {
+    let mut dirs = dirs
+        .iter()
+        .map(|d| {
+            fs::create_dir_all(d)?;
+            Ok(fs::canonicalize(d)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    dirs.sort();
+    dirs.dedup();
+    dirs.iter().map(|d| lock(d, wait)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_excludes_second_holder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let held = lock_all(&[dir, dir], false).unwrap();
+        assert_eq!(held.len(), 1);
+        assert!(lock_all(&[dir], false).is_err());
+        drop(held);
+        assert!(lock_all(&[dir], false).is_ok());
+    }
+}
@@ -1,5 +1,11 @@
+mod atomic;
 mod config;
+mod hash;
+mod interrupt;
+mod lock;
 mod mtime;
+mod progress;
+mod prune;
 mod track;
 
 use anyhow::Result;
@@ -8,27 +14,110 @@ use funcfmt::{fm, FormatPieces, ToFormatPieces};
 use id3::TagLike;
 use jwalk::WalkDir;
 use rayon::prelude::*;
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
 
-use config::Config;
+use config::{Command, Config};
+use progress::Progress;
+use track::feat::{FeatRules, FeatStyle};
+use track::fixers::FixOptions;
+use track::rename::{PathOptions, RenameContext};
 use track::{get_track, Track};
 
-const ALLOWED_EXTS: &[&str] = &["mp3", "flac", "m4a"];
+/// Only MP3 files carry ID3v2 tags as their native format. Others like FLAC and M4A have their own
+/// tags, which mack doesn't understand, and writing an ID3v2 tag into them would corrupt them.
+const ALLOWED_EXTS: &[&str] = &["mp3"];
 
-fn fix_track(track: &mut Track, dry_run: bool) {
-    let fix_results = track::fixers::run_fixers(track, dry_run);
-    match fix_results {
-        Ok(applied_fixers) => {
-            if applied_fixers {
+fn write_track(track: &Track, changed: bool, opts: &FixOptions) {
+    match track::fixers::write_fixes(track, changed, opts) {
+        Ok(report) => {
+            if report.tags_changed {
                 print_updated_tags(track);
             }
+            if !report.stray_tags.is_empty() {
+                println!(
+                    "{}: fixed stray tags: {}",
+                    track.path.display(),
+                    report.stray_tags.join(", ")
+                );
+            }
         }
         Err(err) => eprintln!("cannot fix {}: {:?}", track.path.display(), err),
     }
 }
 
+/// Fixes the tracks from one directory together, so that album-level fixes can see the whole
+/// album. Tracks which are `done` (by an interrupted run) are only used for album-level context,
+/// and aren't written again.
+fn fix_tracks(tracks: &mut [Track], done: &[bool], opts: &FixOptions) {
+    let mut fixed: Vec<_> = tracks
+        .iter_mut()
+        .zip(done)
+        .filter_map(
+            |(track, &done)| match track::fixers::fix_tags(track, opts) {
+                Ok(fixes) => {
+                    if !done {
+                        for warning in &fixes.warnings {
+                            println!("{}: {}", track.path.display(), warning);
+                        }
+                    }
+                    Some((track, fixes.changed, done))
+                }
+                Err(err) => {
+                    eprintln!("cannot fix {}: {:?}", track.path.display(), err);
+                    None
+                }
+            },
+        )
+        .collect();
+
+    let mut tags: Vec<_> = fixed
+        .iter_mut()
+        .map(|(track, _, _)| &mut track.tag)
+        .collect();
+    let report = track::album::fix_album(
+        &mut tags,
+        &opts.feat,
+        opts.id3_version,
+        opts.fix_album_dates_genres,
+    );
+
+    for outlier in report.outliers.iter().filter(|o| !fixed[o.index].2) {
+        let path = fixed[outlier.index].0.path.display();
+        // Missing values are only ever filled in, never just reported.
+        if outlier.old.is_empty() && outlier.changed {
+            println!(
+                "{}: filled in {} '{}' from the rest of the album",
+                path, outlier.field, outlier.new
+            );
+            continue;
+        }
+        let action = if outlier.changed {
+            "changed to"
+        } else {
+            "most tracks have"
+        };
+        println!(
+            "{}: {} '{}' differs from the rest of the album, {} '{}'",
+            path, outlier.field, outlier.old, action, outlier.new
+        );
+    }
+    if let Some(dir) = fixed.first().and_then(|(track, _, _)| track.path.parent()) {
+        for warning in &report.warnings {
+            println!("{}: {}", dir.display(), warning);
+        }
+    }
+    for ((track, changed, done), album_changed) in fixed.into_iter().zip(report.changed) {
+        if !done {
+            write_track(track, changed || album_changed, opts);
+        }
+    }
+}
+
 fn print_updated_tags(track: &Track) {
     println!(
         "{}: updated tags: artist: '{}', album: '{}', title: '{}'",
@@ -39,17 +128,41 @@ fn print_updated_tags(track: &Track) {
     );
 }
 
-fn rename_track(track: &Track, fp: &FormatPieces<Track>, output_path: &Path, dry_run: bool) {
-    let new_path = track::rename::rename_track(track, fp, output_path, dry_run);
+/// Returns the new path if the track was moved away from its original location. Its tag backup
+/// in `backup_dir`, if any, is moved along with it.
+fn rename_track(
+    track: &Track,
+    fp: &FormatPieces<Track>,
+    output_path: &Path,
+    ctx: &RenameContext,
+    backup_dir: Option<&Path>,
+    dry_run: bool,
+) -> Option<PathBuf> {
+    let new_path = track::rename::rename_track(track, fp, output_path, ctx, dry_run);
 
     match new_path {
-        Ok(Some(new_path)) => println!(
-            "{}: renamed to {}",
-            track.path.display(),
-            new_path.display()
-        ),
-        Ok(None) => (),
-        Err(err) => eprintln!("cannot rename {}: {:?}", track.path.display(), err),
+        Ok(Some(new_path)) => {
+            println!(
+                "{}: renamed to {}",
+                track.path.display(),
+                new_path.display()
+            );
+            if let Some(backup_dir) = backup_dir.filter(|_| !dry_run) {
+                if let Err(err) = track::backup::moved(backup_dir, &track.path, &new_path) {
+                    eprintln!(
+                        "can't move tag backup for {}: {:?}",
+                        new_path.display(),
+                        err
+                    );
+                }
+            }
+            Some(new_path)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            eprintln!("cannot rename {}: {:?}", track.path.display(), err);
+            None
+        }
     }
 }
 
@@ -71,19 +184,100 @@ fn clean_part(path_part: &str) -> String {
 fn get_format_pieces(tmpl: &str) -> Result<funcfmt::FormatPieces<Track>> {
     let formatters = fm!(
         "artist" => |t: &Track| Some(clean_part(t.tag.artist().unwrap_or("Unknown Artist"))),
+        "albumartist" => |t: &Track| Some(clean_part(
+            t.tag.album_artist().or_else(|| t.tag.artist()).unwrap_or("Unknown Artist")
+        )),
         "album" => |t: &Track| Some(clean_part(t.tag.album().unwrap_or("Unknown Album"))),
         "title" => |t: &Track| Some(clean_part(t.tag.title().unwrap_or("Unknown Title"))),
         "track" => |t: &Track| Some(format!("{:02}", t.tag.track().unwrap_or_default())),
+        "disc" => |t: &Track| Some(t.tag.disc().map(|d| d.to_string()).unwrap_or_default()),
+        "year" => |t: &Track| Some(
+            track::date::year(&t.tag).map(|y| y.to_string()).unwrap_or_default()
+        ),
+        "originalyear" => |t: &Track| Some(
+            track::date::original_year(&t.tag).map(|y| y.to_string()).unwrap_or_default()
+        ),
+        "subtitle" => |t: &Track| Some(clean_part(
+            t.tag.get("TIT3").and_then(|f| f.content().text()).unwrap_or_default()
+        )),
     );
 
     Ok(formatters.to_format_pieces(tmpl)?)
 }
 
-fn is_updated_since_last_run(path: &PathBuf, last_run_time: SystemTime) -> bool {
-    mtime::mtime_def_now(path) > last_run_time
+/// Removes a temporary file left behind by a run which was killed midway through a write.
+/// Files without a tag can't be fixed, but with --backup they're still given an (empty) backup, so
+/// that if they're tagged elsewhere and fixed by a later run, restoring them takes them back to how
+/// mack first found them.
+fn back_up_tagless(path: &Path, err: &anyhow::Error, opts: &FixOptions) {
+    let no_tag = err
+        .downcast_ref::<id3::Error>()
+        .is_some_and(|e| matches!(e.kind, id3::ErrorKind::NoTag));
+    let Some(backup_dir) = opts
+        .backup_dir
+        .as_deref()
+        .filter(|_| no_tag && !opts.dry_run)
+    else {
+        return;
+    };
+    if let Err(err) = track::backup::save(path, backup_dir) {
+        eprintln!("can't back up tags for {}: {:?}", path.display(), err);
+    }
+}
+
+/// Deals with temporary files under `root` which were left behind by runs that didn't finish.
+/// Most are partial writes, which can be removed since the file they were replacing is still in
+/// place. An interrupted case-only rename leaves a file or directory only under its temporary
+/// name, though, so those are moved on to where they were going instead.
+fn clean_stale_temps(root: &Path) {
+    let stale: Vec<_> = WalkDir::new(root)
+        .skip_hidden(false)
+        .into_iter()
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| atomic::is_stale_temp(p))
+        .collect();
+    for path in stale {
+        let Some(target) = atomic::temp_target(&path) else {
+            continue;
+        };
+        let (result, action) = if target.exists() {
+            (fs::remove_file(&path), "removed stale temporary file")
+        } else {
+            (fs::rename(&path, &target), "finished interrupted rename")
+        };
+        match result {
+            Ok(()) => println!("{}: {}", path.display(), action),
+            Err(err) => eprintln!("can't clean up {}: {:?}", path.display(), err),
+        }
+    }
+}
+
+/// With `use_ctime`, the inode change time is checked as well as the mtime, since files retagged
+/// with --preserve-times (by us or anything else) can keep an mtime from before the last run.
+fn is_updated_since_last_run(path: &PathBuf, last_run_time: SystemTime, use_ctime: bool) -> bool {
+    let changed = if use_ctime {
+        mtime::change_time_def_now(path)
+    } else {
+        mtime::mtime_def_now(path)
+    };
+    changed > last_run_time
 }
 
 fn fix_all_tracks(cfg: &Config, base_path: &PathBuf, output_path: &Path) {
+    // Dry runs don't change anything, so there's nothing to race on.
+    let _locks = if cfg.dry_run {
+        Vec::new()
+    } else {
+        match lock::lock_all(&[base_path, output_path], cfg.wait) {
+            Ok(locks) => locks,
+            Err(err) => {
+                eprintln!("error: {err:#}");
+                return;
+            }
+        }
+    };
+
     // If the output path is different, we don't know if we should run or not, so just do them all
     let last_run_time = if output_path == base_path {
         mtime::get_last_run_time(base_path).unwrap_or(SystemTime::UNIX_EPOCH)
@@ -100,12 +294,97 @@ fn fix_all_tracks(cfg: &Config, base_path: &PathBuf, output_path: &Path) {
         }
     };
 
-    WalkDir::new(base_path)
+    let protected_artists = match track::feat::load_protected_artists(
+        cfg.protected_artists.as_deref(),
+        cfg.builtin_protected_artists,
+    ) {
+        Ok(artists) => artists,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            return;
+        }
+    };
+
+    let genre_map = match track::genre::load_genre_map(cfg.genre_map.as_deref()) {
+        Ok(map) => map,
+        Err(err) => {
+            eprintln!("error: {err:#}");
+            return;
+        }
+    };
+
+    let rules = cfg.fs_profile.rules();
+    // Probing creates a file, so in dry run mode just assume the profile matches the filesystem.
+    // Otherwise, locking has already created the output directory, so it can be probed itself.
+    let case_insensitive_fs = if cfg.dry_run {
+        rules.case_insensitive
+    } else {
+        track::rename::is_case_insensitive_fs(output_path).unwrap_or(rules.case_insensitive)
+    };
+    let fix_opts = FixOptions {
+        dry_run: cfg.dry_run,
+        preserve_times: cfg.preserve_times,
+        id3_version: cfg.id3_version.map(Into::into),
+        stray_tags: cfg.stray_tags,
+        feat: FeatRules::new(cfg.feat_keywords.clone(), &protected_artists),
+        feat_style: FeatStyle {
+            brackets: cfg.feat_brackets,
+            keyword: cfg.feat_keyword.clone(),
+            separator: cfg.feat_separator.clone(),
+            conjunction: cfg.feat_conjunction.clone(),
+            oxford_comma: !cfg.no_oxford_comma,
+        },
+        qualifiers: cfg.qualifiers,
+        genre_map,
+        fix_dates: !cfg.no_fix_dates,
+        fix_album_dates_genres: cfg.fix_album_dates_genres,
+        backup_dir: cfg
+            .backup
+            .then(|| track::backup::make_backup_dir(output_path)),
+    };
+    let ctx = RenameContext::new(
+        PathOptions {
+            rules,
+            transliterate: cfg.transliterate,
+            max_part_len: cfg.max_part_len,
+            part_len_unit: cfg.part_len_unit,
+            part_len_includes_ext: cfg.part_len_includes_ext,
+            truncate_at_word: cfg.truncate_at_word,
+        },
+        case_insensitive_fs,
+    );
+
+    let progress = match Progress::load(base_path, cfg.dry_run) {
+        Ok(progress) => progress,
+        Err(err) => {
+            eprintln!("can't load progress for {}: {:?}", base_path.display(), err);
+            return;
+        }
+    };
+    if progress.resumed() > 0 {
+        println!(
+            "{}: resuming interrupted run, skipping {} already processed files",
+            base_path.display(),
+            progress.resumed()
+        );
+    }
+
+    // We hold the locks, so any temporary files left in these directories are from runs which
+    // didn't finish.
+    if !cfg.dry_run {
+        clean_stale_temps(base_path);
+        if !output_path.starts_with(base_path) {
+            clean_stale_temps(output_path);
+        }
+    }
+
+    let all_paths = WalkDir::new(base_path)
         .skip_hidden(false)
         .into_iter()
         .filter_map(Result::ok)
         .filter(|e| e.file_type().is_file())
         .map(|e| e.path())
+        .filter(|e| !atomic::is_stale_temp(e))
         .filter(|e| {
             let ext = e
                 .extension()
@@ -114,16 +393,114 @@ fn fix_all_tracks(cfg: &Config, base_path: &PathBuf, output_path: &Path) {
                 .to_lowercase();
             ALLOWED_EXTS.iter().any(|a| a == &ext)
         })
-        .filter(|e| cfg.force || is_updated_since_last_run(e, last_run_time))
-        .collect::<Vec<_>>()
+        .collect::<Vec<_>>();
+
+    // Album-level fixes need to see the whole album, so if anything in a directory was updated,
+    // everything in it is processed. Files finished by an interrupted run are still loaded for
+    // that, but aren't written again.
+    let updated_dirs: HashSet<_> = all_paths
+        .iter()
+        .filter(|e| cfg.force || is_updated_since_last_run(e, last_run_time, cfg.preserve_times))
+        .filter_map(|e| e.parent())
+        .collect();
+    let mut dirs: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for path in all_paths
+        .iter()
+        .filter(|e| e.parent().is_some_and(|p| updated_dirs.contains(p)))
+    {
+        let done = progress.is_done(path);
+        dirs.entry(path.parent())
+            .or_default()
+            .push((path.clone(), done));
+    }
+    let total = dirs.values().flatten().filter(|(_, done)| !done).count();
+    let completed = AtomicUsize::new(0);
+
+    let record = |path: &Path, new_path: Option<&PathBuf>| {
+        if let Err(err) = progress.record(new_path.map_or(path, PathBuf::as_path)) {
+            eprintln!("can't record progress for {}: {:?}", path.display(), err);
+        }
+        completed.fetch_add(1, Ordering::Relaxed);
+    };
+
+    let moved_from = dirs
         .into_par_iter()
-        .for_each(|path| match get_track(path.clone()) {
-            Ok(mut track) => {
-                fix_track(&mut track, cfg.dry_run);
-                rename_track(&track, &fp, output_path, cfg.dry_run);
+        .flat_map_iter(|(_, paths)| {
+            // Let anything in flight finish, but don't start on anything new.
+            if interrupt::is_interrupted() {
+                return Vec::new();
             }
-            Err(err) => eprintln!("error: {}: {err:?}", path.display()),
-        });
+
+            let mut tracks = Vec::new();
+            let mut done = Vec::new();
+            for (path, is_done) in paths {
+                match get_track(path.clone()) {
+                    Ok(track) => {
+                        tracks.push(track);
+                        done.push(is_done);
+                    }
+                    Err(err) => {
+                        if !is_done {
+                            eprintln!("error: {}: {err:?}", path.display());
+                            back_up_tagless(&path, &err, &fix_opts);
+                            record(&path, None);
+                        }
+                    }
+                }
+            }
+            fix_tracks(&mut tracks, &done, &fix_opts);
+
+            tracks
+                .iter()
+                .zip(done)
+                .filter(|(_, done)| !done)
+                .filter_map(|(track, _)| {
+                    let new_path = rename_track(
+                        track,
+                        &fp,
+                        output_path,
+                        &ctx,
+                        fix_opts.backup_dir.as_deref(),
+                        cfg.dry_run,
+                    );
+                    record(&track.path, new_path.as_ref());
+                    new_path.and(track.path.parent().map(Path::to_path_buf))
+                })
+                .collect()
+        })
+        .collect::<Vec<_>>();
+
+    // Fixing the case of directories renames them, so only do it once nothing else is moving
+    // tracks into them.
+    for dir in ctx.case_fix_dirs() {
+        if let Err(err) = ctx.fix_dir_case(output_path, &dir) {
+            eprintln!("can't fix case of {}: {:?}", dir.display(), err);
+        }
+    }
+
+    if cfg.prune_empty_dirs && !cfg.dry_run {
+        for dir in prune::prune_empty_dirs(base_path, moved_from, &cfg.prune_junk) {
+            println!("{}: removed empty directory", dir.display());
+        }
+    }
+
+    if interrupt::is_interrupted() {
+        eprintln!(
+            "{}: interrupted after {} of {} files, run again to resume",
+            base_path.display(),
+            completed.into_inner(),
+            total
+        );
+        return;
+    }
+
+    if let Err(err) = progress.finish(base_path) {
+        eprintln!(
+            "can't clear progress for {}: {:?}",
+            base_path.display(),
+            err
+        );
+    }
 
     if !cfg.dry_run && output_path == base_path {
         mtime::set_last_run_time(base_path).unwrap_or_else(|err| {
@@ -136,13 +513,50 @@ fn fix_all_tracks(cfg: &Config, base_path: &PathBuf, output_path: &Path) {
     }
 }
 
+/// Returns whether every track was restored.
+fn restore_tracks(paths: &[PathBuf], wait: bool, dry_run: bool) -> bool {
+    let mut ok = true;
+    for path in paths {
+        match track::backup::restore(path, wait, dry_run) {
+            Ok(backup) => println!(
+                "{}: restored tags from {}",
+                path.display(),
+                backup.display()
+            ),
+            Err(err) => {
+                eprintln!("cannot restore {}: {:?}", path.display(), err);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
 fn main() {
     let mut cfg = Config::parse();
 
+    if let Some(Command::Restore { paths }) = &cfg.command {
+        if !restore_tracks(paths, cfg.wait, cfg.dry_run) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let paths = cfg.paths.take().unwrap_or_else(|| vec![PathBuf::from(".")]);
 
+    if let Err(err) = interrupt::install_handler() {
+        eprintln!("can't install interrupt handler: {err:?}");
+    }
+
     for path in paths {
+        if interrupt::is_interrupted() {
+            break;
+        }
         let output_path = cfg.output_dir.clone().unwrap_or_else(|| path.clone());
         fix_all_tracks(&cfg, &path, &output_path);
     }
+
+    if interrupt::is_interrupted() {
+        std::process::exit(130);
+    }
 }
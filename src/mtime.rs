@@ -1,7 +1,7 @@
 use anyhow::Result;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 const LASTMACK_NAME: &str = ".lastmack";
 
@@ -27,6 +27,32 @@ pub fn set_last_run_time(base_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// The inode change time, which (unlike mtime) can't be set from userspace, so it still advances
+/// when a file is moved into place or retagged with its mtime preserved.
+#[cfg(target_family = "unix")]
+fn get_ctime(stat: &fs::Metadata) -> SystemTime {
+    use std::os::unix::fs::MetadataExt;
+    let secs = u64::try_from(stat.ctime()).unwrap_or_default();
+    let nsecs = u32::try_from(stat.ctime_nsec()).unwrap_or_default();
+    SystemTime::UNIX_EPOCH + Duration::new(secs, nsecs)
+}
+
+/// Windows has no ctime, but the creation time is at least updated when a file is copied in.
+#[cfg(target_family = "windows")]
+fn get_ctime(stat: &fs::Metadata) -> SystemTime {
+    stat.created().unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// When the file was last changed in any way, whether or not its mtime reflects that.
+fn get_change_time<T: AsRef<Path>>(path: T) -> Result<SystemTime> {
+    let stat = fs::metadata(path.as_ref())?;
+    Ok(stat.modified()?.max(get_ctime(&stat)))
+}
+
 pub fn mtime_def_now<T: AsRef<Path>>(path: T) -> SystemTime {
     get_mtime(path.as_ref()).unwrap_or_else(|_| SystemTime::now())
 }
+
+pub fn change_time_def_now<T: AsRef<Path>>(path: T) -> SystemTime {
+    get_change_time(path.as_ref()).unwrap_or_else(|_| SystemTime::now())
+}
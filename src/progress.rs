@@ -0,0 +1,190 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const PROGRESS_NAME: &str = ".mackprogress";
+
+fn make_progress_path(base_path: &Path) -> PathBuf {
+    let mut progress_path = base_path.to_path_buf();
+    progress_path.push(PROGRESS_NAME);
+    progress_path
+}
+
+/// Identifies the state of a file when it was completed, so that it's only skipped if it hasn't
+/// changed since.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileState {
+    size: u64,
+    /// Nanoseconds since the epoch.
+    mtime: u128,
+}
+
+impl FileState {
+    fn of(path: &Path) -> Result<Self> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        Ok(Self {
+            size: meta.len(),
+            mtime,
+        })
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(target_family = "unix")]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+// Windows paths which aren't valid Unicode are vanishingly rare, so they're simply never matched.
+#[cfg(target_family = "windows")]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(target_family = "windows")]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Parses an entry written by `Progress::record`, returning None if it's malformed, as the last
+/// one may be if we crashed while writing it.
+fn parse_entry(entry: &[u8]) -> Option<(PathBuf, FileState)> {
+    let mut fields = entry.splitn(3, |&b| b == b' ');
+    let mut number = || {
+        std::str::from_utf8(fields.next()?)
+            .ok()?
+            .parse::<u128>()
+            .ok()
+    };
+    let size = u64::try_from(number()?).ok()?;
+    let mtime = number()?;
+    let path = fields.next().filter(|p| !p.is_empty())?;
+    Some((path_from_bytes(path), FileState { size, mtime }))
+}
+
+/// Tracks which files have been completed during a run, so that if the run is interrupted (or
+/// crashes), the next run can skip straight past them.
+///
+/// Each entry is the file's size, its mtime, and its path as raw bytes, separated by spaces.
+/// Entries are NUL separated, since that's the only thing that can't appear in a path.
+pub struct Progress {
+    done: HashMap<PathBuf, FileState>,
+    file: Option<Mutex<fs::File>>,
+}
+
+impl Progress {
+    /// Loads progress left over from a previous, unfinished run. Unless `dry_run` is set, newly
+    /// completed files will also be appended to it.
+    pub fn load(base_path: &Path, dry_run: bool) -> Result<Self> {
+        let progress_path = make_progress_path(base_path);
+        let done = match fs::read(&progress_path) {
+            Ok(data) => data.split(|&b| b == 0).filter_map(parse_entry).collect(),
+            Err(_) => HashMap::new(),
+        };
+        let file = if dry_run {
+            None
+        } else {
+            Some(Mutex::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&progress_path)?,
+            ))
+        };
+        Ok(Self { done, file })
+    }
+
+    /// How many files were already completed by a previous run.
+    pub fn resumed(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Whether `path` was completed by a previous run, and hasn't changed since.
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done
+            .get(path)
+            .is_some_and(|state| FileState::of(path).ok().as_ref() == Some(state))
+    }
+
+    /// Records that the file which is now at `path` needs no further work in this run.
+    pub fn record(&self, path: &Path) -> Result<()> {
+        if let Some(file) = &self.file {
+            let state = FileState::of(path)?;
+            let mut entry = format!("{} {} ", state.size, state.mtime).into_bytes();
+            entry.extend(path_to_bytes(path));
+            entry.push(0);
+            file.lock()
+                .expect("BUG: progress file poisoned")
+                .write_all(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// The run completed, so there's nothing left to resume.
+    pub fn finish(self, base_path: &Path) -> Result<()> {
+        if self.file.is_some() {
+            fs::remove_file(make_progress_path(base_path))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_resume() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        fs::create_dir_all(base.join("Artist")).unwrap();
+        let done = base.join("Artist/01 Title\nwith newline.mp3");
+        let changed = base.join("Artist/02 Title.mp3");
+        fs::write(&done, b"done").unwrap();
+        fs::write(&changed, b"changed").unwrap();
+
+        let progress = Progress::load(base, false).unwrap();
+        progress.record(&done).unwrap();
+        progress.record(&changed).unwrap();
+        drop(progress);
+        fs::write(&changed, b"changed again").unwrap();
+
+        let progress = Progress::load(base, false).unwrap();
+        assert!(progress.is_done(&done));
+        assert!(!progress.is_done(&changed));
+        assert!(!progress.is_done(&base.join("Artist/03 Title.mp3")));
+        assert_eq!(progress.resumed(), 2);
+        progress.finish(base).unwrap();
+        assert!(!make_progress_path(base).exists());
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(
+            parse_entry(b"12 34 a b.mp3"),
+            Some((
+                PathBuf::from("a b.mp3"),
+                FileState {
+                    size: 12,
+                    mtime: 34
+                }
+            ))
+        );
+        assert_eq!(parse_entry(b"/music/a.mp3"), None);
+        assert_eq!(parse_entry(b"12 3"), None);
+    }
+}
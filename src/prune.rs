@@ -0,0 +1,135 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Whether a directory entry should be ignored when deciding if a directory is empty.
+fn is_junk(name: &str, junk_files: &[String]) -> bool {
+    junk_files.iter().any(|j| j.eq_ignore_ascii_case(name))
+}
+
+/// Whether a directory entry is a junk file.
+fn is_junk_entry(entry: &fs::DirEntry, junk_files: &[String]) -> Result<bool> {
+    let is_file = entry.file_type()?.is_file();
+    Ok(is_file
+        && entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| is_junk(name, junk_files)))
+}
+
+/// Returns true if the directory contains nothing other than junk files.
+fn is_effectively_empty(dir: &Path, junk_files: &[String]) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if !is_junk_entry(&entry?, junk_files)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Removes a directory which only contains junk files, along with the junk files themselves.
+///
+/// Each entry is checked again before it's removed, since something else could have been put in
+/// the directory since it was found to be empty. If anything other than junk turns up, the
+/// directory is left alone and false is returned.
+fn remove_dir_with_junk(dir: &Path, junk_files: &[String]) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !is_junk_entry(&entry, junk_files)? {
+            return Ok(false);
+        }
+        fs::remove_file(entry.path())?;
+    }
+    fs::remove_dir(dir)?;
+    Ok(true)
+}
+
+/// Walks up from each directory that had files moved out of it, removing any that are now empty
+/// (or only contain junk files). Never removes `base_path` itself, or anything outside of it.
+///
+/// Returns the directories that were removed.
+pub fn prune_empty_dirs(
+    base_path: &Path,
+    source_dirs: impl IntoIterator<Item = PathBuf>,
+    junk_files: &[String],
+) -> Vec<PathBuf> {
+    // Reverse order means children are always visited before their parents.
+    let source_dirs: BTreeSet<_> = source_dirs.into_iter().collect();
+    let mut removed = Vec::new();
+
+    for dir in source_dirs.into_iter().rev() {
+        let mut cur = Some(dir.as_path());
+        while let Some(dir) = cur {
+            if dir == base_path || !dir.starts_with(base_path) {
+                break;
+            }
+            match is_effectively_empty(dir, junk_files) {
+                Ok(true) => (),
+                Ok(false) => break,
+                // Already removed through another source dir
+                Err(_) if !dir.exists() => break,
+                Err(err) => {
+                    eprintln!("cannot prune {}: {:?}", dir.display(), err);
+                    break;
+                }
+            }
+            match remove_dir_with_junk(dir, junk_files) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(err) => {
+                    eprintln!("cannot prune {}: {:?}", dir.display(), err);
+                    break;
+                }
+            }
+            removed.push(dir.to_path_buf());
+            cur = dir.parent();
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_junk_case_insensitive() {
+        let junk = vec!["Thumbs.db".to_owned(), "desktop.ini".to_owned()];
+        assert!(is_junk("thumbs.db", &junk));
+        assert!(is_junk("Desktop.INI", &junk));
+        assert!(!is_junk("01 Song.mp3", &junk));
+    }
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let album = base.join("Artist/Album");
+        let kept = base.join("Artist/Other");
+        fs::create_dir_all(&album).unwrap();
+        fs::create_dir_all(&kept).unwrap();
+        fs::write(album.join("Thumbs.db"), b"").unwrap();
+        fs::write(kept.join("01 Song.mp3"), b"").unwrap();
+
+        let junk = vec!["Thumbs.db".to_owned()];
+        let removed = prune_empty_dirs(base, vec![album.clone(), kept.clone()], &junk);
+
+        assert_eq!(removed, vec![album.clone()]);
+        assert!(!album.exists());
+        assert!(kept.exists());
+        assert!(base.exists());
+    }
+
+    #[test]
+    fn test_remove_dir_with_junk_keeps_other_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join("01 Song.mp3"), b"").unwrap();
+
+        let junk = vec!["Thumbs.db".to_owned()];
+        assert!(!remove_dir_with_junk(dir, &junk).unwrap());
+        assert!(dir.join("01 Song.mp3").exists());
+    }
+}
@@ -0,0 +1,673 @@
+use crate::track::feat::{extract_feat, FeatRules};
+use crate::track::fixers::{artist_key, fix_artist};
+use crate::track::number::Position;
+use crate::track::qualifier::extract_qualifiers;
+use crate::track::version::target_version;
+use id3::{Tag, TagLike, Version};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// A track whose tag disagreed with the rest of its album.
+pub struct Outlier {
+    /// The track's index in the slice passed to `fix_album`.
+    pub index: usize,
+    pub field: &'static str,
+    /// The old value, which is empty if there wasn't one.
+    pub old: String,
+    /// What most of the album has.
+    pub new: String,
+    /// Whether the track was changed to match, rather than only being reported.
+    pub changed: bool,
+}
+
+/// What `fix_album` changed.
+pub struct AlbumReport {
+    /// Whether each tag was changed.
+    pub changed: Vec<bool>,
+    pub outliers: Vec<Outlier>,
+    /// Problems which can't be fixed automatically, like missing tracks.
+    pub warnings: Vec<String>,
+}
+
+impl AlbumReport {
+    fn record(
+        &mut self,
+        index: usize,
+        field: &'static str,
+        old: Option<String>,
+        new: &str,
+        changed: bool,
+    ) {
+        self.changed[index] |= changed;
+        self.outliers.push(Outlier {
+            index,
+            field,
+            old: old.unwrap_or_default(),
+            new: new.to_owned(),
+            changed,
+        });
+    }
+}
+
+/// What `reconcile` does with tracks which disagree with the majority of their album.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reconcile {
+    /// Change values which differ, but leave tracks without a value alone.
+    Outliers,
+    /// Change values which differ, and give tracks without a value the majority's.
+    OutliersAndMissing,
+    /// Only report values which differ.
+    Report,
+}
+
+/// Returns the most common value by `artist_key`, using the first spelling seen of it. Ties go to
+/// whichever was seen first.
+fn most_common<'a>(values: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let mut counts: HashMap<String, (usize, usize, &str)> = HashMap::new();
+    for (i, value) in values.into_iter().enumerate() {
+        counts.entry(artist_key(value)).or_insert((0, i, value)).0 += 1;
+    }
+    counts
+        .into_values()
+        .max_by_key(|&(count, first, _)| (count, std::cmp::Reverse(first)))
+        .map(|(_, _, value)| value)
+}
+
+/// Splits the tags at `indices` into groups which share the same key. Tags without a key aren't
+/// put in any group.
+fn group_by(
+    tags: &[&mut Tag],
+    indices: &[usize],
+    key: impl Fn(&Tag) -> Option<String>,
+) -> Vec<Vec<usize>> {
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for &i in indices {
+        if let Some(key) = key(tags[i]) {
+            groups.entry(key).or_default().push(i);
+        }
+    }
+    groups.into_values().collect()
+}
+
+/// Finds the value of a field which a strict majority of the tags in `group` with a value agree on,
+/// if there is one, and changes or reports the tags which differ according to `mode`.
+fn reconcile(
+    tags: &mut [&mut Tag],
+    group: &[usize],
+    field: &'static str,
+    get: impl Fn(&Tag) -> Option<String>,
+    set: impl Fn(&mut Tag, &str),
+    mode: Reconcile,
+    report: &mut AlbumReport,
+) {
+    let values: Vec<_> = group.iter().map(|&i| get(tags[i])).collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for value in values.iter().flatten() {
+        *counts.entry(value).or_default() += 1;
+    }
+    let present: usize = counts.values().sum();
+    let Some((majority, count)) = counts.into_iter().max_by_key(|&(_, count)| count) else {
+        return;
+    };
+    if count * 2 <= present {
+        return;
+    }
+
+    let majority = majority.to_owned();
+    for (&i, value) in group.iter().zip(&values) {
+        let is_outlier = match value {
+            Some(value) => *value != majority,
+            None => mode == Reconcile::OutliersAndMissing,
+        };
+        if is_outlier {
+            let change = mode != Reconcile::Report;
+            if change {
+                set(tags[i], &majority);
+            }
+            report.record(i, field, value.clone(), &majority, change);
+        }
+    }
+}
+
+/// Returns the value which more than half of `sets` contain by `artist_key`, using the first
+/// spelling seen of it.
+fn covering_most<'a>(sets: &[Vec<&'a str>]) -> Option<&'a str> {
+    let mut counts: HashMap<String, (usize, usize, &str)> = HashMap::new();
+    let mut order = 0;
+    for set in sets {
+        let mut seen = BTreeSet::new();
+        for &value in set {
+            let key = artist_key(value);
+            if seen.insert(key.clone()) {
+                counts.entry(key).or_insert((0, order, value)).0 += 1;
+                order += 1;
+            }
+        }
+    }
+    counts
+        .into_values()
+        .filter(|&(count, _, _)| count * 2 > sets.len())
+        .max_by_key(|&(count, first, _)| (count, std::cmp::Reverse(first)))
+        .map(|(_, _, value)| value)
+}
+
+/// Returns the artists credited on a track, without any featured artists. ID3v2.3 has no real
+/// separator for multiple artists, so id3 reads the "/" in names like "AC/DC" as one and joins the
+/// parts with "\0". That's put back, since it's far more often part of a name than a separator.
+fn primary_artists(tag: &Tag, feat: &FeatRules) -> Option<Vec<String>> {
+    let artist = tag.artist()?;
+    let values: Vec<_> = match tag.version() {
+        Version::Id3v22 | Version::Id3v23 => vec![artist.replace('\0', "/")],
+        Version::Id3v24 => artist.split('\0').map(String::from).collect(),
+    };
+    let artists: Vec<_> = values
+        .iter()
+        .map(|value| extract_feat(value.trim(), feat).title)
+        .filter(|value| !value.is_empty())
+        .collect();
+    (!artists.is_empty()).then_some(artists)
+}
+
+/// Works out the album artist for an album's worth of tracks: the most common existing album
+/// artist if any of them have one, otherwise whichever artist is credited on most of the tracks
+/// (ignoring featured artists), or "Various Artists" if no artist is.
+fn choose_album_artist(tags: &[&mut Tag], group: &[usize], feat: &FeatRules) -> Option<String> {
+    let album_artists = group.iter().filter_map(|&i| tags[i].album_artist());
+    if let Some(existing) = most_common(album_artists) {
+        return Some(existing.to_owned());
+    }
+
+    let credits: Vec<_> = group
+        .iter()
+        .filter_map(|&i| primary_artists(tags[i], feat))
+        .collect();
+    if credits.is_empty() {
+        return None;
+    }
+
+    // Whole credits go first, so that a duo credited on every track isn't cut down to one of them.
+    let whole: Vec<_> = credits.iter().map(|c| c.join("\0")).collect();
+    let whole_sets: Vec<_> = whole.iter().map(|w| vec![w.as_str()]).collect();
+    if let Some(artist) = covering_most(&whole_sets) {
+        return Some(artist.to_owned());
+    }
+
+    let names: Vec<Vec<String>> = credits
+        .iter()
+        .map(|c| c.iter().flat_map(|a| feat.split_artists(a)).collect())
+        .collect();
+    let name_sets: Vec<_> = names
+        .iter()
+        .map(|n| n.iter().map(String::as_str).collect())
+        .collect();
+    Some(
+        covering_most(&name_sets)
+            .unwrap_or(VARIOUS_ARTISTS)
+            .to_owned(),
+    )
+}
+
+fn is_compilation(tag: &Tag) -> bool {
+    tag.get("TCMP")
+        .and_then(|f| f.content().text())
+        .is_some_and(|t| t.trim() == "1")
+}
+
+/// Fixes the album artist (TPE2) and compilation flag (TCMP) across all of the tracks in `group`.
+fn fix_album_artist(
+    tags: &mut [&mut Tag],
+    group: &[usize],
+    feat: &FeatRules,
+    report: &mut AlbumReport,
+) {
+    for &i in group {
+        if let Some(new_album_artist) = fix_artist(tags[i].album_artist(), feat) {
+            tags[i].set_album_artist(new_album_artist);
+            report.changed[i] = true;
+        }
+    }
+
+    reconcile(
+        tags,
+        group,
+        "album artist",
+        |t| t.album_artist().map(String::from),
+        |t, v| t.set_album_artist(v),
+        Reconcile::Outliers,
+        report,
+    );
+
+    let Some(album_artist) = choose_album_artist(tags, group, feat) else {
+        return;
+    };
+    let compilation = album_artist == VARIOUS_ARTISTS;
+    for &i in group {
+        if tags[i].album_artist().is_none() {
+            tags[i].set_album_artist(&album_artist);
+            report.changed[i] = true;
+        }
+        if compilation && !is_compilation(tags[i]) {
+            tags[i].set_text("TCMP", "1");
+            report.changed[i] = true;
+        }
+    }
+}
+
+/// Turns vinyl positions like "A1" and "B2" into track numbers, if all of the tracks in `group`
+/// have them.
+fn fix_vinyl_numbers(tags: &mut [&mut Tag], group: &[usize], report: &mut AlbumReport) {
+    let mut sides = Vec::new();
+    for &i in group {
+        let position = tags[i]
+            .get("TRCK")
+            .and_then(|f| f.content().text())
+            .and_then(Position::parse);
+        match position {
+            Some(Position::Vinyl(side, number)) => sides.push((side, number, i)),
+            Some(Position::Number(..)) => return,
+            None => {}
+        }
+    }
+    sides.sort_unstable();
+    for (number, (_, _, i)) in (1..).zip(sides) {
+        tags[i].set_track(number);
+        report.changed[i] = true;
+    }
+}
+
+/// The highest track number we look for gaps up to, so that a bogus total like "1/65535" doesn't
+/// produce an endless list of missing tracks.
+const MAX_TRACK_NUMBER: u32 = 999;
+
+/// Returns the numbers from 1 to `last` (or `MAX_TRACK_NUMBER`, if that's lower) which aren't in
+/// `numbers`, and the numbers which are in `numbers` more than once.
+fn find_gaps_and_duplicates(numbers: &[u32], last: u32) -> (Vec<u32>, Vec<u32>) {
+    let mut seen = BTreeSet::new();
+    let mut duplicates = BTreeSet::new();
+    for &number in numbers {
+        if !seen.insert(number) {
+            duplicates.insert(number);
+        }
+    }
+    let gaps = (1..=last.min(MAX_TRACK_NUMBER))
+        .filter(|n| !seen.contains(n))
+        .collect();
+    (gaps, duplicates.into_iter().collect())
+}
+
+fn join_numbers(numbers: &[u32]) -> String {
+    numbers
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reconciles the track and disc totals for an album, and warns about missing or duplicated track
+/// numbers. Totals are never worked out from the tracks alone, since the album may be incomplete.
+fn fix_numbers(tags: &mut [&mut Tag], group: &[usize], report: &mut AlbumReport) {
+    fix_vinyl_numbers(tags, group, report);
+
+    let with_disc: Vec<_> = group
+        .iter()
+        .copied()
+        .filter(|&i| tags[i].disc().is_some())
+        .collect();
+    reconcile(
+        tags,
+        &with_disc,
+        "disc total",
+        |t| t.total_discs().map(|n| n.to_string()),
+        |t, v| t.set_total_discs(v.parse().unwrap_or_default()),
+        Reconcile::OutliersAndMissing,
+        report,
+    );
+
+    let Some(album) = group.first().and_then(|&i| tags[i].album()) else {
+        return;
+    };
+    let album = album.to_owned();
+    let mut by_disc: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for &i in group.iter().filter(|&&i| tags[i].track().is_some()) {
+        by_disc.entry(tags[i].disc()).or_default().push(i);
+    }
+    for (disc, tracks) in by_disc {
+        reconcile(
+            tags,
+            &tracks,
+            "track total",
+            |t| t.total_tracks().map(|n| n.to_string()),
+            |t, v| t.set_total_tracks(v.parse().unwrap_or_default()),
+            Reconcile::OutliersAndMissing,
+            report,
+        );
+
+        let numbers: Vec<_> = tracks.iter().filter_map(|&i| tags[i].track()).collect();
+        let total = tracks.iter().find_map(|&i| tags[i].total_tracks());
+        let last = numbers.iter().copied().max().unwrap_or_default();
+        let (gaps, duplicates) = find_gaps_and_duplicates(&numbers, total.unwrap_or(0).max(last));
+
+        let on_disc = disc.map(|d| format!(" on disc {d}")).unwrap_or_default();
+        if !gaps.is_empty() {
+            report.warnings.push(format!(
+                "album '{album}' is missing track numbers {}{on_disc}",
+                join_numbers(&gaps)
+            ));
+        }
+        if !duplicates.is_empty() {
+            report.warnings.push(format!(
+                "album '{album}' has more than one track numbered {}{on_disc}",
+                join_numbers(&duplicates)
+            ));
+        }
+    }
+}
+
+/// Makes the tracks from one directory consistent with each other: album titles, album artists,
+/// dates, genres, and track and disc totals are reconciled to what the majority of each album
+/// has, and missing album artists are filled in.
+///
+/// Since a directory may hold more than one album, only tracks whose album titles match (ignoring
+/// qualifiers like "Remastered") are reconciled with each other.
+///
+/// Dates and genres can legitimately differ between tracks, so those which disagree with the rest
+/// of the album are only reported, unless `fix_dates_genres` is set. They're left alone entirely
+/// on compilations.
+pub fn fix_album(
+    tags: &mut [&mut Tag],
+    feat: &FeatRules,
+    version: Option<Version>,
+    fix_dates_genres: bool,
+) -> AlbumReport {
+    let mut report = AlbumReport {
+        changed: vec![false; tags.len()],
+        outliers: Vec::new(),
+        warnings: Vec::new(),
+    };
+    let all: Vec<_> = (0..tags.len()).collect();
+
+    let title_groups = group_by(tags, &all, |t| {
+        t.album()
+            .map(|a| artist_key(&extract_qualifiers(a, feat).0))
+    });
+    for group in title_groups {
+        reconcile(
+            tags,
+            &group,
+            "album",
+            |t| t.album().map(String::from),
+            |t, v| t.set_album(v),
+            Reconcile::Outliers,
+            &mut report,
+        );
+    }
+
+    // Tracks without an album are most likely unrelated singles, so they're left alone.
+    let album_groups = group_by(tags, &all, |t| t.album().map(artist_key));
+
+    let date_frame = |t: &Tag| {
+        if target_version(version, t) == Version::Id3v24 {
+            "TDRC"
+        } else {
+            "TYER"
+        }
+    };
+    let mode = if fix_dates_genres {
+        Reconcile::OutliersAndMissing
+    } else {
+        Reconcile::Report
+    };
+    for group in album_groups {
+        fix_album_artist(tags, &group, feat, &mut report);
+        fix_numbers(tags, &group, &mut report);
+        let compilation = group
+            .iter()
+            .any(|&i| is_compilation(tags[i]) || tags[i].album_artist() == Some(VARIOUS_ARTISTS));
+        if compilation {
+            continue;
+        }
+        reconcile(
+            tags,
+            &group,
+            "date",
+            |t| {
+                t.get(date_frame(t))
+                    .and_then(|f| f.content().text())
+                    .map(String::from)
+            },
+            |t, v| t.set_text(date_frame(t), v),
+            mode,
+            &mut report,
+        );
+        reconcile(
+            tags,
+            &group,
+            "genre",
+            |t| t.genre().map(String::from),
+            |t, v| t.set_genre(v),
+            mode,
+            &mut report,
+        );
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_tag(artist: &str, album_artist: Option<&str>) -> Tag {
+        let mut tag = Tag::new();
+        tag.set_artist(artist);
+        tag.set_album("Baz");
+        if let Some(album_artist) = album_artist {
+            tag.set_album_artist(album_artist);
+        }
+        tag
+    }
+
+    fn fix(tags: &mut [&mut Tag]) -> AlbumReport {
+        fix_album(tags, &FeatRules::default(), None, false)
+    }
+
+    #[test]
+    fn test_fix_album_artist_same_artist() {
+        let mut a = make_tag("Foo Bar", None);
+        let mut b = make_tag("foo bar", None);
+        let report = fix(&mut [&mut a, &mut b]);
+
+        assert_eq!(report.changed, [true, true]);
+        assert_eq!(a.album_artist(), Some("Foo Bar"));
+        assert_eq!(b.album_artist(), Some("Foo Bar"));
+        assert!(!is_compilation(&a));
+    }
+
+    #[test]
+    fn test_fix_album_artist_compilation() {
+        let mut a = make_tag("Foo Bar", None);
+        let mut b = make_tag("Baz Qux", None);
+        fix(&mut [&mut a, &mut b]);
+
+        assert_eq!(a.album_artist(), Some(VARIOUS_ARTISTS));
+        assert!(is_compilation(&a));
+        assert!(is_compilation(&b));
+        assert_eq!(fix(&mut [&mut a, &mut b]).changed, [false, false]);
+    }
+
+    #[test]
+    fn test_fix_album_artist_mostly_one_artist() {
+        let mut a = make_tag("Foo Bar", None);
+        let mut b = make_tag("Foo Bar & Baz Qux (feat. Quux)", None);
+        let mut c = make_tag("Corge", None);
+        fix(&mut [&mut a, &mut b, &mut c]);
+
+        assert_eq!(c.album_artist(), Some("Foo Bar"));
+        assert!(!is_compilation(&a));
+        assert!(!is_compilation(&c));
+    }
+
+    #[test]
+    fn test_fix_album_artist_feat_only_differs() {
+        let mut a = make_tag("Foo Bar", None);
+        let mut b = make_tag("Foo Bar feat. Baz Qux", None);
+        fix(&mut [&mut a, &mut b]);
+
+        assert_eq!(b.album_artist(), Some("Foo Bar"));
+        assert!(!is_compilation(&b));
+    }
+
+    #[test]
+    fn test_fix_album_artist_v23_slash() {
+        let mut a = Tag::with_version(Version::Id3v23);
+        a.set_album("Baz");
+        // This is how id3 reads "AC/DC" from a v2.3 TPE1 frame.
+        a.set_artist("AC\0DC");
+        let mut b = a.clone();
+        fix(&mut [&mut a, &mut b]);
+
+        assert_eq!(a.album_artist(), Some("AC/DC"));
+        assert!(!is_compilation(&a));
+    }
+
+    #[test]
+    fn test_fix_album_singles() {
+        let mut a = make_tag("Foo Bar", None);
+        let mut b = make_tag("Baz Qux", None);
+        a.remove_album();
+        b.remove_album();
+        a.set_genre("Rock");
+        let report = fix(&mut [&mut a, &mut b]);
+
+        assert_eq!(report.changed, [false, false]);
+        assert!(b.album_artist().is_none());
+        assert!(!is_compilation(&b));
+        assert!(b.genre().is_none());
+    }
+
+    #[test]
+    fn test_fix_album_artist_existing_with_feat() {
+        let mut a = make_tag("Foo Bar", Some("Foo Bar feat. Baz Qux"));
+        let mut b = make_tag("Foo Bar", None);
+        let report = fix(&mut [&mut a, &mut b]);
+
+        assert_eq!(report.changed, [true, true]);
+        assert_eq!(a.album_artist(), Some("Foo Bar"));
+        assert_eq!(b.album_artist(), Some("Foo Bar"));
+    }
+
+    fn make_album(tracks: &[&str]) -> Vec<Tag> {
+        tracks
+            .iter()
+            .map(|&track| {
+                let mut tag = make_tag("Foo Bar", None);
+                tag.set_text("TRCK", track);
+                tag
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fix_album_vinyl_numbers() {
+        let mut tags = make_album(&["B1", "A2", "A1"]);
+        let report = fix(&mut tags.iter_mut().collect::<Vec<_>>());
+
+        let numbers: Vec<_> = tags.iter().map(|t| t.track()).collect();
+        assert_eq!(numbers, [Some(3), Some(2), Some(1)]);
+        assert!(tags.iter().all(|t| t.total_tracks().is_none()));
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_fix_album_track_totals() {
+        let mut tags = make_album(&["1", "2", "3"]);
+        fix(&mut tags.iter_mut().collect::<Vec<_>>());
+        assert!(tags.iter().all(|t| t.total_tracks().is_none()));
+
+        let mut tags = make_album(&["1/4", "2", "3/4"]);
+        let report = fix(&mut tags.iter_mut().collect::<Vec<_>>());
+        assert!(tags.iter().all(|t| t.total_tracks() == Some(4)));
+        assert_eq!(report.warnings, ["album 'Baz' is missing track numbers 4"]);
+
+        let mut tags = make_album(&["1", "3", "3"]);
+        let report = fix(&mut tags.iter_mut().collect::<Vec<_>>());
+        assert!(tags.iter().all(|t| t.total_tracks().is_none()));
+        assert_eq!(
+            report.warnings,
+            [
+                "album 'Baz' is missing track numbers 2",
+                "album 'Baz' has more than one track numbered 3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_gaps_and_duplicates_bogus_total() {
+        let (gaps, duplicates) = find_gaps_and_duplicates(&[1, 2, 2], u32::MAX);
+        assert_eq!(gaps.len(), MAX_TRACK_NUMBER as usize - 2);
+        assert_eq!(gaps.last(), Some(&MAX_TRACK_NUMBER));
+        assert_eq!(duplicates, [2]);
+    }
+
+    fn make_abbey_road() -> Vec<Tag> {
+        let mut tags: Vec<_> = (0..3).map(|_| make_tag("Foo Bar", None)).collect();
+        for tag in &mut tags {
+            tag.set_album("Abbey Road");
+            tag.set_genre("Rock");
+        }
+        tags[1].set_album("Abbey Road (Remastered)");
+        tags[2].set_genre("Pop");
+        tags
+    }
+
+    #[test]
+    fn test_fix_album_majority() {
+        let mut tags = make_abbey_road();
+        let mut other = make_tag("Foo Bar", None);
+        other.set_album("Let It Be");
+        other.set_genre("Pop");
+
+        let mut refs: Vec<_> = tags.iter_mut().chain([&mut other]).collect();
+        let report = fix(&mut refs);
+
+        assert!(tags.iter().all(|t| t.album() == Some("Abbey Road")));
+        assert_eq!(tags[2].genre(), Some("Pop"));
+        assert_eq!(other.album(), Some("Let It Be"));
+        assert_eq!(other.genre(), Some("Pop"));
+        let outliers: Vec<_> = report
+            .outliers
+            .iter()
+            .map(|o| (o.index, o.field, o.old.as_str(), o.changed))
+            .collect();
+        assert_eq!(
+            outliers,
+            [
+                (1, "album", "Abbey Road (Remastered)", true),
+                (2, "genre", "Pop", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fix_album_majority_fix_dates_genres() {
+        let mut tags = make_abbey_road();
+        let mut refs: Vec<_> = tags.iter_mut().collect();
+        let report = fix_album(&mut refs, &FeatRules::default(), None, true);
+
+        assert!(tags.iter().all(|t| t.genre() == Some("Rock")));
+        assert!(report.outliers.iter().all(|o| o.changed));
+    }
+
+    #[test]
+    fn test_fix_album_compilation_genres() {
+        let mut tags = make_abbey_road();
+        tags[0].set_artist("Baz Qux");
+        tags[1].set_artist("Quux");
+        let mut refs: Vec<_> = tags.iter_mut().collect();
+        let report = fix_album(&mut refs, &FeatRules::default(), None, true);
+
+        assert_eq!(tags[2].genre(), Some("Pop"));
+        assert!(report.outliers.iter().all(|o| o.field != "genre"));
+    }
+}
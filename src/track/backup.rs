@@ -0,0 +1,263 @@
+use crate::atomic;
+use crate::hash::Fnv1a;
+use crate::lock;
+use crate::track::stray;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const BACKUP_DIR: &str = ".mack/backups";
+
+pub fn make_backup_dir(base_path: &Path) -> PathBuf {
+    base_path.join(BACKUP_DIR)
+}
+
+/// Identifies a track by its audio data (everything other than its tags), so that the same backup
+/// is found no matter how the file is renamed or retagged.
+fn audio_id(file: &mut fs::File, audio: &Range<u64>) -> Result<String> {
+    file.seek(SeekFrom::Start(audio.start))?;
+    let mut reader = BufReader::new(&*file).take(audio.end - audio.start);
+    let mut hasher = Fnv1a::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Identifies where a track is, so that different copies of the same audio get their own backups.
+/// The parent is canonicalised rather than the file itself, so that this also works for a path
+/// which a file has just been moved away from.
+fn path_id(path: &Path) -> Result<String> {
+    let parent = path.parent().context("path has no parent")?;
+    let parent = fs::canonicalize(if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    })?;
+    let name = path.file_name().context("path has no file name")?;
+    let mut hasher = Fnv1a::default();
+    hasher.write(parent.join(name).as_os_str().as_encoded_bytes());
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Saves the tags currently in the file at `path` to `backup_dir`, unless a backup for this track
+/// already exists. The first backup is never overwritten, so it always holds the tags from before
+/// mack first touched the file.
+///
+/// Everything before the audio (the ID3v2 tag, and any duplicates) is saved as `<id>.id3`, and
+/// everything after it (APEv2 and ID3v1 tags) as `<id>.tail`, so that restoring puts back stray
+/// tags too. Files without any tags get an empty backup, so that restoring them removes the tags
+/// again.
+///
+/// Backups are keyed by both where the file is and its audio, so copies of the same audio in
+/// different places don't share a backup. `moved` keeps them in step when mack moves a file.
+pub fn save(path: &Path, backup_dir: &Path) -> Result<()> {
+    let mut file = fs::File::open(path)?;
+    let audio = stray::scan(&mut file)?.audio;
+    let backup_path = backup_dir
+        .join(path_id(path)?)
+        .join(format!("{}.id3", audio_id(&mut file, &audio)?));
+    if backup_path.exists() {
+        return Ok(());
+    }
+
+    let lead = stray::read_range(&mut file, 0..audio.start)?;
+    let len = file.metadata()?.len();
+    let tail = stray::read_range(&mut file, audio.end..len)?;
+    fs::create_dir_all(backup_path.parent().context("backup has no parent")?)?;
+    // The tail goes first, since the lead existing is what marks the backup as complete.
+    if !tail.is_empty() {
+        atomic::write_file(&backup_path.with_extension("tail"), &tail)?;
+    }
+    atomic::write_file(&backup_path, &lead)
+}
+
+/// Moves the backups for the file which was at `from` to go with it to `to`.
+pub fn moved(backup_dir: &Path, from: &Path, to: &Path) -> Result<()> {
+    let from_dir = backup_dir.join(path_id(from)?);
+    let Ok(entries) = fs::read_dir(&from_dir) else {
+        return Ok(());
+    };
+    let to_dir = backup_dir.join(path_id(to)?);
+    fs::create_dir_all(&to_dir)?;
+    for entry in entries {
+        let entry = entry?;
+        fs::rename(entry.path(), to_dir.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&from_dir)?;
+    atomic::sync_parent(&to_dir)
+}
+
+/// Finds the backup for the file at `path` in the closest backup directory above it. If the file
+/// was moved by something other than mack, a backup of the same audio from elsewhere is used, as
+/// long as there's only one.
+///
+/// Returns the directory which the backup directory belongs to, and the backup itself.
+fn find(path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let mut file = fs::File::open(path)?;
+    let audio = stray::scan(&mut file)?.audio;
+    let name = format!("{}.id3", audio_id(&mut file, &audio)?);
+    let id = path_id(path)?;
+
+    let path = fs::canonicalize(path)?;
+    for run_dir in path.ancestors().skip(1) {
+        let backup_dir = make_backup_dir(run_dir);
+        let exact = backup_dir.join(&id).join(&name);
+        if exact.exists() {
+            return Ok((run_dir.to_path_buf(), exact));
+        }
+        let Ok(entries) = fs::read_dir(&backup_dir) else {
+            continue;
+        };
+        let mut candidates = entries
+            .filter_map(Result::ok)
+            .map(|e| e.path().join(&name))
+            .filter(|p| p.exists());
+        match (candidates.next(), candidates.next()) {
+            (Some(only), None) => return Ok((run_dir.to_path_buf(), only)),
+            (Some(_), Some(_)) => bail!(
+                "several backups of this audio in {}, and none for this path",
+                backup_dir.display()
+            ),
+            _ => {}
+        }
+    }
+    bail!("no tag backup found")
+}
+
+fn read_optional(path: &Path) -> Result<Vec<u8>> {
+    match fs::read(path) {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Restores the tags of the file at `path` from its backup, returning the backup used.
+///
+/// This takes the same lock as a normal run on the directory the backup belongs to, so that it
+/// can't race with one. If another instance holds it, either wait for it or fail, depending on
+/// `wait`.
+pub fn restore(path: &Path, wait: bool, dry_run: bool) -> Result<PathBuf> {
+    let (run_dir, backup_path) = find(path)?;
+    if dry_run {
+        return Ok(backup_path);
+    }
+    let _lock = lock::lock_all(&[&run_dir], wait)?;
+    let lead = fs::read(&backup_path)?;
+    let tail = read_optional(&backup_path.with_extension("tail"))?;
+
+    atomic::modify_via_temp(path, |tmp| {
+        let audio = stray::scan(&mut fs::File::open(tmp)?)?.audio;
+        let data = fs::read(tmp)?;
+        let audio = usize::try_from(audio.start)?..usize::try_from(audio.end)?;
+
+        let mut out = Vec::with_capacity(lead.len() + audio.len() + tail.len());
+        out.extend_from_slice(&lead);
+        out.extend_from_slice(&data[audio]);
+        out.extend_from_slice(&tail);
+        Ok(fs::write(tmp, out)?)
+    })?;
+    Ok(backup_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::writer::write_tag;
+    use id3::{Tag, TagLike, Version};
+
+    #[test]
+    fn test_backup_and_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        fs::create_dir_all(base.join("Artist")).unwrap();
+        let path = base.join("Artist/01 Title.mp3");
+
+        let mut tag = Tag::new();
+        tag.set_title("Original");
+        let mut data = Vec::new();
+        tag.write_to(&mut data, Version::Id3v23).unwrap();
+        data.extend_from_slice(b"\xff\xfb\x90\x64not really audio");
+        fs::write(&path, data).unwrap();
+
+        let backup_dir = make_backup_dir(base);
+        save(&path, &backup_dir).unwrap();
+
+        tag.set_title("Changed");
+        write_tag(&tag, &path, Version::Id3v24).unwrap();
+        // The second backup must not overwrite the original.
+        save(&path, &backup_dir).unwrap();
+
+        restore(&path, false, false).unwrap();
+        let restored = Tag::read_from_path(&path).unwrap();
+        assert_eq!(restored.title(), Some("Original"));
+        assert_eq!(restored.version(), Version::Id3v23);
+    }
+
+    fn write_track(path: &Path, title: Option<&str>) {
+        let mut data = Vec::new();
+        if let Some(title) = title {
+            let mut tag = Tag::new();
+            tag.set_title(title);
+            tag.write_to(&mut data, Version::Id3v24).unwrap();
+        }
+        data.extend_from_slice(b"\xff\xfb\x90\x64not really audio");
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_backup_same_audio_and_moves() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let a = base.join("a.mp3");
+        let b = base.join("b.mp3");
+        write_track(&a, Some("A"));
+        write_track(&b, None);
+
+        let backup_dir = make_backup_dir(base);
+        save(&a, &backup_dir).unwrap();
+        save(&b, &backup_dir).unwrap();
+
+        let moved_b = base.join("moved.mp3");
+        fs::rename(&b, &moved_b).unwrap();
+        moved(&backup_dir, &b, &moved_b).unwrap();
+        write_tag(&Tag::new(), &a, Version::Id3v24).unwrap();
+        write_tag(&Tag::new(), &moved_b, Version::Id3v24).unwrap();
+
+        restore(&a, false, false).unwrap();
+        restore(&moved_b, false, false).unwrap();
+        assert_eq!(Tag::read_from_path(&a).unwrap().title(), Some("A"));
+        assert!(Tag::read_from_path(&moved_b).is_err());
+    }
+
+    #[test]
+    fn test_backup_stray_tags_and_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let path = base.join("a.mp3");
+        write_track(&path, Some("A"));
+        let mut id3v1 = b"TAG".to_vec();
+        id3v1.resize(128, 0);
+        let original = [fs::read(&path).unwrap(), id3v1].concat();
+        fs::write(&path, &original).unwrap();
+
+        let backup_dir = make_backup_dir(base);
+        save(&path, &backup_dir).unwrap();
+        write_track(&path, Some("B"));
+
+        let held = lock::lock_all(&[base], false).unwrap();
+        assert!(restore(&path, false, false).is_err());
+        drop(held);
+        restore(&path, false, false).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), original);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::track::version::{parse_tdat, parse_time};
+use id3::{Tag, TagLike, Timestamp, Version};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Dates like "2011-03-04", "2011-00-00", "2011/3", "20110304", or "2011-03-04T12:30".
+static DATE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(\d{4})(?:[-/.]?(\d{1,2})(?:[-/.]?(\d{1,2}))?)?(?:[T ](\d{2}):(\d{2})(?::(\d{2}))?)?$",
+    )
+    .expect("BUG: Invalid date regex")
+});
+/// A year somewhere in a less tidy value, like "c. 1999" or "1999 (reissue)".
+static YEAR_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\b([12]\d{3})\b").expect("BUG: Invalid year regex"));
+
+/// Frames holding the recording date, or the original release date, in either ID3v2 version.
+const RECORDING_FRAMES: &[&str] = &["TDRC", "TYER", "TDAT", "TIME"];
+const ORIGINAL_FRAMES: &[&str] = &["TDOR", "TORY"];
+
+fn text<'a>(tag: &'a Tag, id: &str) -> Option<&'a str> {
+    tag.get(id).and_then(|f| f.content().text())
+}
+
+/// Parses the kinds of dates found in the wild into a timestamp. Months and days of zero, like in
+/// "2011-00-00", mean that part is unknown and are left out, but anything else out of range means
+/// the value isn't a date at all.
+pub fn parse_date(text: &str) -> Option<Timestamp> {
+    let text = text.trim();
+    let Some(caps) = DATE_RE.captures(text) else {
+        let year = YEAR_RE.captures(text)?[1].parse().ok()?;
+        return Some(Timestamp {
+            year,
+            month: None,
+            day: None,
+            hour: None,
+            minute: None,
+            second: None,
+        });
+    };
+
+    let mut parts = [None; 5];
+    for (part, (i, max)) in parts
+        .iter_mut()
+        .zip([(2, 12), (3, 31), (4, 23), (5, 59), (6, 59)])
+    {
+        if let Some(m) = caps.get(i) {
+            let n: u8 = m.as_str().parse().ok()?;
+            if n > max {
+                return None;
+            }
+            *part = Some(n);
+        }
+    }
+    let [month, day, hour, minute, second] = parts;
+    let month = month.filter(|&m| m > 0);
+    let day = month.and(day).filter(|&d| d > 0);
+    let hour = day.and(hour);
+    let minute = hour.and(minute);
+    Some(Timestamp {
+        year: caps[1].parse().ok()?,
+        month,
+        day,
+        hour,
+        minute,
+        second: minute.and(second),
+    })
+}
+
+fn parse_frame(tag: &Tag, id: &str) -> Result<Option<Timestamp>, String> {
+    match text(tag, id) {
+        Some(value) => parse_date(value)
+            .map(Some)
+            .ok_or_else(|| format!("invalid {id} '{value}'")),
+        None => Ok(None),
+    }
+}
+
+/// Reads the recording date from TDRC, or from TYER, TDAT, and TIME. Returns a description of the
+/// problem if any of them hold something which isn't a valid date or time, so that they aren't
+/// quietly dropped.
+fn recording_date(tag: &Tag) -> Result<Option<Timestamp>, String> {
+    if tag.get("TDRC").is_some() {
+        return parse_frame(tag, "TDRC");
+    }
+    let Some(mut ts) = parse_frame(tag, "TYER")? else {
+        return match ["TDAT", "TIME"]
+            .into_iter()
+            .find(|id| tag.get(id).is_some())
+        {
+            Some(id) => Err(format!("{id} without a year in TYER")),
+            None => Ok(None),
+        };
+    };
+    if ts.month.is_none() {
+        if let Some(value) = text(tag, "TDAT") {
+            let (day, month) =
+                parse_tdat(value).ok_or_else(|| format!("invalid TDAT '{value}'"))?;
+            ts.day = Some(day);
+            ts.month = Some(month);
+            if let Some(value) = text(tag, "TIME") {
+                let (hour, minute) =
+                    parse_time(value).ok_or_else(|| format!("invalid TIME '{value}'"))?;
+                ts.hour = Some(hour);
+                ts.minute = Some(minute);
+            }
+        }
+    }
+    Ok(Some(ts))
+}
+
+fn original_date(tag: &Tag) -> Result<Option<Timestamp>, String> {
+    match ORIGINAL_FRAMES.iter().find(|id| tag.get(id).is_some()) {
+        Some(id) => parse_frame(tag, id),
+        None => Ok(None),
+    }
+}
+
+/// Returns the first date in `frames` which can be parsed, ignoring any which can't.
+fn first_date(tag: &Tag, frames: &[&str]) -> Option<Timestamp> {
+    frames
+        .iter()
+        .find_map(|id| text(tag, id).and_then(parse_date))
+}
+
+/// Returns the year the track was recorded, if known.
+pub fn year(tag: &Tag) -> Option<i32> {
+    first_date(tag, &["TDRC", "TYER"]).map(|ts| ts.year)
+}
+
+/// Returns the year the track was originally released, falling back to the year it was recorded.
+pub fn original_year(tag: &Tag) -> Option<i32> {
+    first_date(tag, ORIGINAL_FRAMES)
+        .map(|ts| ts.year)
+        .or_else(|| year(tag))
+}
+
+/// Sets (or with None, removes) a text frame, returning whether that changed anything.
+fn set_text(tag: &mut Tag, id: &str, value: Option<String>) -> bool {
+    if text(tag, id) == value.as_deref() {
+        return false;
+    }
+    match value {
+        Some(value) => tag.set_text(id, value),
+        None => {
+            tag.remove(id);
+        }
+    }
+    true
+}
+
+/// Returns the frames to write a recording date and original release date as for `version`.
+fn date_frames(
+    recorded: Option<Timestamp>,
+    original: Option<Timestamp>,
+    version: Version,
+) -> Vec<(&'static str, Option<String>)> {
+    if version != Version::Id3v23 {
+        return vec![
+            ("TDRC", recorded.map(|ts| ts.to_string())),
+            ("TDOR", original.map(|ts| ts.to_string())),
+        ];
+    }
+
+    let date = recorded.and_then(|ts| Some(format!("{:02}{:02}", ts.day?, ts.month?)));
+    let time = recorded
+        .filter(|_| date.is_some())
+        .and_then(|ts| Some(format!("{:02}{:02}", ts.hour?, ts.minute?)));
+    vec![
+        ("TYER", recorded.map(|ts| format!("{:04}", ts.year))),
+        ("TDAT", date),
+        ("TIME", time),
+        ("TORY", original.map(|ts| format!("{:04}", ts.year))),
+    ]
+}
+
+/// Rewrites the recording and original release dates as canonical ISO 8601 dates (or their
+/// ID3v2.3 equivalents) in the frames for `version`, removing any frames left over from the other
+/// version. Returns whether anything changed.
+///
+/// If any of the date frames can't be made sense of, they're all left alone rather than losing
+/// what they hold, and the problem is added to `warnings`.
+pub fn fix_dates(tag: &mut Tag, version: Version, warnings: &mut Vec<String>) -> bool {
+    let (recorded, original) = match (recording_date(tag), original_date(tag)) {
+        (Ok(recorded), Ok(original)) => (recorded, original),
+        (Err(err), _) | (_, Err(err)) => {
+            warnings.push(format!("{err}, leaving dates alone"));
+            return false;
+        }
+    };
+
+    let frames = date_frames(recorded, original, version);
+    let mut changed = false;
+    for id in RECORDING_FRAMES.iter().chain(ORIGINAL_FRAMES) {
+        let value = frames
+            .iter()
+            .find(|(frame_id, _)| frame_id == id)
+            .and_then(|(_, value)| value.clone());
+        changed |= set_text(tag, id, value);
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Option<String> {
+        parse_date(text).map(|ts| ts.to_string())
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse("2011-00-00"), Some("2011".to_owned()));
+        assert_eq!(parse("20110304"), Some("2011-03-04".to_owned()));
+        assert_eq!(parse("2011/3"), Some("2011-03".to_owned()));
+        assert_eq!(parse("c. 1999"), Some("1999".to_owned()));
+        assert_eq!(
+            parse("2011-03-04T12:30"),
+            Some("2011-03-04T12:30".to_owned())
+        );
+        assert_eq!(parse("unknown"), None);
+        assert_eq!(parse("2011-13-04"), None);
+        assert_eq!(parse("2011-03-32"), None);
+    }
+
+    #[test]
+    fn test_fix_dates_invalid_tdat() {
+        for (tdat, time) in [("0000", None), ("3113", None), ("0403", Some("2460"))] {
+            let mut tag = Tag::with_version(Version::Id3v23);
+            tag.set_text("TYER", "2011");
+            tag.set_text("TDAT", tdat);
+            if let Some(time) = time {
+                tag.set_text("TIME", time);
+            }
+            let mut warnings = Vec::new();
+            assert!(!fix_dates(&mut tag, Version::Id3v24, &mut warnings));
+
+            assert_eq!(warnings.len(), 1);
+            assert_eq!(text(&tag, "TDAT"), Some(tdat));
+            assert!(tag.get("TDRC").is_none());
+        }
+    }
+
+    #[test]
+    fn test_fix_dates_v24() {
+        let mut tag = Tag::with_version(Version::Id3v23);
+        tag.set_text("TYER", "c. 1999");
+        tag.set_text("TORY", "1995");
+        assert!(fix_dates(&mut tag, Version::Id3v24, &mut Vec::new()));
+
+        assert_eq!(text(&tag, "TDRC"), Some("1999"));
+        assert_eq!(text(&tag, "TDOR"), Some("1995"));
+        assert!(tag.get("TYER").is_none());
+        assert!(tag.get("TORY").is_none());
+        assert!(!fix_dates(&mut tag, Version::Id3v24, &mut Vec::new()));
+    }
+
+    #[test]
+    fn test_fix_dates_v23() {
+        let mut tag = Tag::with_version(Version::Id3v24);
+        tag.set_text("TDRC", "20110304");
+        assert!(fix_dates(&mut tag, Version::Id3v23, &mut Vec::new()));
+
+        assert_eq!(text(&tag, "TYER"), Some("2011"));
+        assert_eq!(text(&tag, "TDAT"), Some("0403"));
+        assert!(tag.get("TDRC").is_none());
+        assert_eq!(original_year(&tag), Some(2011));
+    }
+}
@@ -1,7 +1,304 @@
+use crate::track::qualifier::bracketed_sections;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
 
-const FEAT_KEYWORDS: &[&str] = &["feat", "ft", "f.", "featuring"];
+/// The keywords recognised when none are configured.
+pub const DEFAULT_FEAT_KEYWORDS: &str = "feat,ft,f.,featuring";
+
+/// Well known artists whose names would otherwise be split up as if they were several artists.
+const BUILTIN_PROTECTED_ARTISTS: &[&str] = &[
+    "Angus & Julia Stone",
+    "Belle and Sebastian",
+    "Bob Marley & the Wailers",
+    "Brooks & Dunn",
+    "Chase & Status",
+    "Crosby, Stills & Nash",
+    "Crosby, Stills, Nash & Young",
+    "Derek and the Dominos",
+    "Earth, Wind & Fire",
+    "Echo & the Bunnymen",
+    "Emerson, Lake & Palmer",
+    "Eric B. & Rakim",
+    "Florence and the Machine",
+    "Gladys Knight & the Pips",
+    "Hall & Oates",
+    "Hootie & the Blowfish",
+    "Huey Lewis and the News",
+    "Ike & Tina Turner",
+    "Iron & Wine",
+    "Joan Jett & the Blackhearts",
+    "Katrina and the Waves",
+    "Kool & the Gang",
+    "Marina and the Diamonds",
+    "Martha and the Vandellas",
+    "Mumford & Sons",
+    "Nick Cave & the Bad Seeds",
+    "Of Monsters and Men",
+    "Peter, Paul and Mary",
+    "Prince and the Revolution",
+    "Sam & Dave",
+    "Simon & Garfunkel",
+    "Siouxsie and the Banshees",
+    "Sly and the Family Stone",
+    "Sonny & Cher",
+    "Tom Petty and the Heartbreakers",
+    "Tyler, the Creator",
+];
+
+/// Words which describe a kind of remix, rather than who made it, as in "Extended Remix". Numbers,
+/// as in "2015 Remix", are treated the same way.
+const GENERIC_REMIX_WORDS: &[&str] = &[
+    "album",
+    "club",
+    "dub",
+    "extended",
+    "instrumental",
+    "mono",
+    "new",
+    "official",
+    "original",
+    "radio",
+    "remaster",
+    "remastered",
+    "single",
+    "stereo",
+    "the",
+    "vocal",
+];
+
+static REMIX_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)^(?:(?P<before>.+?)(?:'s)?\s+(?:remix|rmx)|remix(?:ed)?\s+by\s+(?P<after>.+))$",
+    )
+    .expect("BUG: Invalid remix regex")
+});
+
+static PRODUCER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^prod(?:\.|uced)?(?:\s+by)?\s+(?P<by>.+)$")
+        .expect("BUG: Invalid producer regex")
+});
+
+/// Where a feat keyword has to be for it to be recognised.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    /// Anywhere that it isn't part of a larger word.
+    Word,
+    /// Only surrounded by whitespace, for short keywords like "x".
+    Space,
+    /// Only at the start of a bracketed section, for keywords like "with" which are too common in
+    /// ordinary titles otherwise.
+    Bracket,
+}
+
+/// A keyword introducing featured artists, parsed from "keyword" or "keyword:boundary".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeatKeyword {
+    pub keyword: String,
+    pub boundary: Boundary,
+}
+
+impl FromStr for FeatKeyword {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (keyword, boundary) = s.split_once(':').unwrap_or((s, "word"));
+        let keyword = keyword.trim().to_lowercase();
+        if keyword.is_empty() {
+            bail!("empty feat keyword");
+        }
+        let boundary = match boundary {
+            "word" => Boundary::Word,
+            "space" => Boundary::Space,
+            "bracket" => Boundary::Bracket,
+            other => bail!("unknown boundary '{other}', expected word, space, or bracket"),
+        };
+        Ok(Self { keyword, boundary })
+    }
+}
+
+impl FeatKeyword {
+    /// Whether `text`, which must already be lowercase, starts with this keyword.
+    fn starts(&self, text: &str) -> bool {
+        let Some(rest) = text.strip_prefix(self.keyword.as_str()) else {
+            return false;
+        };
+        let next = rest.chars().next();
+        match self.boundary {
+            Boundary::Space => next.is_some_and(char::is_whitespace),
+            // Keywords like "f." already end in their own boundary.
+            Boundary::Word | Boundary::Bracket => {
+                !self.keyword.ends_with(char::is_alphanumeric)
+                    || next.map_or(true, |c| !c.is_alphanumeric())
+            }
+        }
+    }
+}
+
+/// The configured feat keywords, along with a regex to find them outside of brackets.
+pub struct FeatRules {
+    keywords: Vec<FeatKeyword>,
+    unbracketed_re: Option<Regex>,
+    /// Lowercased artist names which must never be split.
+    protected_artists: Vec<String>,
+}
+
+impl FeatRules {
+    pub fn new(keywords: Vec<FeatKeyword>, protected_artists: &[String]) -> Self {
+        let alternation = |boundary| {
+            let mut matching: Vec<_> = keywords
+                .iter()
+                .filter(|k| k.boundary == boundary)
+                .map(|k| regex::escape(&k.keyword))
+                .collect();
+            // Longest first, so that "featuring" wins over "feat".
+            matching.sort_by_key(|k| std::cmp::Reverse(k.len()));
+            matching.join("|")
+        };
+
+        let mut patterns = Vec::new();
+        let word = alternation(Boundary::Word);
+        if !word.is_empty() {
+            patterns.push(format!(r"(?:^|\W)(?P<word>{word})(?:$|\W)"));
+        }
+        let space = alternation(Boundary::Space);
+        if !space.is_empty() {
+            patterns.push(format!(r"\s(?P<space>{space})\s"));
+        }
+        let unbracketed_re = (!patterns.is_empty()).then(|| {
+            Regex::new(&format!("(?i){}", patterns.join("|"))).expect("BUG: Invalid feat regex")
+        });
+
+        Self {
+            keywords,
+            unbracketed_re,
+            protected_artists: protected_artists.iter().map(|a| a.to_lowercase()).collect(),
+        }
+    }
+}
+
+impl FeatRules {
+    /// Splits a credit like "Foo, Bar & Baz" into its individual artists.
+    pub fn split_artists(&self, artists: &str) -> Vec<String> {
+        split_artists(artists, &self.protected_artists)
+    }
+
+    /// Whether the content of a bracketed section is a feat clause, like "feat. Foo".
+    pub fn is_feat_section(&self, content: &str) -> bool {
+        starts_with_keyword(content, &self.keywords)
+    }
+}
+
+/// Returns who made a remix if `qualifier` is a remix credit, like "Foo Remix" or "Remix by
+/// Foo".
+pub fn parse_remixer(qualifier: &str) -> Option<String> {
+    let caps = REMIX_RE.captures(qualifier.trim())?;
+    let remixer = caps.name("before").or_else(|| caps.name("after"))?.as_str();
+    let is_generic = remixer.split_whitespace().all(|w| {
+        w.chars().all(|c| c.is_ascii_digit())
+            || GENERIC_REMIX_WORDS
+                .iter()
+                .any(|g| g.eq_ignore_ascii_case(w))
+    });
+    (!is_generic).then(|| remixer.trim().to_owned())
+}
+
+/// Whether `text` is a producer credit, like "prod. Foo" or "Produced by Foo".
+pub fn is_producer_credit(text: &str) -> bool {
+    PRODUCER_RE.is_match(text.trim())
+}
+
+/// Splits bracketed producer credits like "(prod. Foo)" or "[Produced by Foo]" out of `title`.
+/// Returns the rest of the title, and who was credited in each one.
+pub fn extract_producers(title: &str) -> (String, Vec<String>) {
+    let mut producers = Vec::new();
+    let mut base = String::new();
+    let mut last_end = 0;
+    for (start, end) in bracketed_sections(title) {
+        if let Some(caps) = PRODUCER_RE.captures(title[start + 1..end - 1].trim()) {
+            producers.push(caps["by"].trim().to_owned());
+            base.push_str(&title[last_end..start]);
+            last_end = end;
+        }
+    }
+    base.push_str(&title[last_end..]);
+
+    let base = base.split_whitespace().collect::<Vec<_>>().join(" ");
+    (base, producers)
+}
+
+/// Reads artist names to protect from splitting from `path`, one per line, with blank lines and
+/// lines starting with "#" ignored. With `builtin`, a list of well known names is included too.
+pub fn load_protected_artists(path: Option<&Path>, builtin: bool) -> Result<Vec<String>> {
+    let mut artists = Vec::new();
+    if builtin {
+        artists.extend(BUILTIN_PROTECTED_ARTISTS.iter().map(|&a| a.to_owned()));
+    }
+    if let Some(path) = path {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("can't read protected artists from {}", path.display()))?;
+        artists.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    Ok(artists)
+}
+
+impl Default for FeatRules {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_FEAT_KEYWORDS
+                .split(',')
+                .map(|k| k.parse().expect("BUG: Invalid default feat keyword"))
+                .collect(),
+            &[],
+        )
+    }
+}
+
+/// The brackets to put around featured artists in titles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FeatBrackets {
+    /// (feat. Artist)
+    Round,
+    /// [feat. Artist]
+    Square,
+    /// feat. Artist
+    #[value(name = "none")]
+    Bare,
+}
+
+/// How featured artists are written into titles.
+#[derive(Clone, Debug)]
+pub struct FeatStyle {
+    pub brackets: FeatBrackets,
+    pub keyword: String,
+    pub separator: String,
+    /// The word joining the last two artists, like "and" or "&".
+    pub conjunction: String,
+    /// Whether to put a separator before the conjunction when there are three or more artists.
+    pub oxford_comma: bool,
+}
+
+impl Default for FeatStyle {
+    fn default() -> Self {
+        Self {
+            brackets: FeatBrackets::Round,
+            keyword: "feat.".to_owned(),
+            separator: ", ".to_owned(),
+            conjunction: "and".to_owned(),
+            oxford_comma: true,
+        }
+    }
+}
 
 /// Represents a track's title after extracting featured artists.
 #[derive(Debug, PartialEq, Eq)]
@@ -19,10 +316,10 @@ struct BracketedFeat<'a> {
 }
 
 /// Extracts featured artist information from a track title.
-pub fn extract_feat(title: &str) -> TrackFeat {
-    if let Some(bf) = find_bracketed_feat(title, FEAT_KEYWORDS) {
-        let artist_part = remove_keyword_from_content(bf.content, FEAT_KEYWORDS);
-        let featured_artists = split_artists(artist_part);
+pub fn extract_feat(title: &str, rules: &FeatRules) -> TrackFeat {
+    if let Some(bf) = find_bracketed_feat(title, &rules.keywords) {
+        let artist_part = remove_keyword_from_content(bf.content, &rules.keywords);
+        let featured_artists = split_artists(artist_part, &rules.protected_artists);
         let base_title = format!(
             "{} {}",
             &title[..bf.open_idx].trim_end(),
@@ -38,10 +335,10 @@ pub fn extract_feat(title: &str) -> TrackFeat {
         };
     }
 
-    if let Some(pos) = find_non_bracketed_feat(title) {
+    if let Some(pos) = find_non_bracketed_feat(title, rules) {
         let inner = &title[pos..].trim();
-        let artist_part = remove_keyword_from_content(inner, FEAT_KEYWORDS);
-        let featured_artists = split_artists(artist_part);
+        let artist_part = remove_keyword_from_content(inner, &rules.keywords);
+        let featured_artists = split_artists(artist_part, &rules.protected_artists);
         let base_title = title[..pos].trim_end().to_string();
         return TrackFeat {
             title: base_title,
@@ -58,10 +355,15 @@ pub fn extract_feat(title: &str) -> TrackFeat {
     }
 }
 
+fn starts_with_keyword(content: &str, keywords: &[FeatKeyword]) -> bool {
+    let lower = content.trim().to_lowercase();
+    keywords.iter().any(|kw| kw.starts(&lower))
+}
+
 /// Searches for a bracketed section (using '(' or '[') whose inner content, when trimmed
 /// and lowercased, starts with one of the specified keywords. If found, returns a `BracketedFeat`
 /// containing the opening index, closing index, and the inner content.
-fn find_bracketed_feat<'a>(title: &'a str, keywords: &[&str]) -> Option<BracketedFeat<'a>> {
+fn find_bracketed_feat<'a>(title: &'a str, keywords: &[FeatKeyword]) -> Option<BracketedFeat<'a>> {
     let mut chars = title.char_indices().peekable();
     while let Some((i, ch)) = chars.next() {
         if ch == '(' || ch == '[' {
@@ -82,10 +384,7 @@ fn find_bracketed_feat<'a>(title: &'a str, keywords: &[&str]) -> Option<Brackete
             if depth == 0 {
                 // Get the inner content (without the brackets).
                 let content = &title[i + ch.len_utf8()..j];
-                if keywords
-                    .iter()
-                    .any(|&kw| content.trim().to_lowercase().starts_with(kw))
-                {
+                if starts_with_keyword(content, keywords) {
                     return Some(BracketedFeat {
                         open_idx: i,
                         close_idx: j,
@@ -100,32 +399,26 @@ fn find_bracketed_feat<'a>(title: &'a str, keywords: &[&str]) -> Option<Brackete
 
 /// Searches for a non-bracketed occurrence of any feature keyword (case–insensitively)
 /// in the title. Returns the earliest index if found.
-fn find_non_bracketed_feat(title: &str) -> Option<usize> {
-    static FEAT_RE: Lazy<Regex> = Lazy::new(|| {
-        let escaped_keywords: Vec<String> =
-            FEAT_KEYWORDS.iter().map(|&s| regex::escape(s)).collect();
-
-        let pattern = format!(r"(?i)(?:^|\W)({})(:?$|\W)", escaped_keywords.join("|"));
-        Regex::new(&pattern).expect("BUG: Invalid feat regex")
-    });
-
-    FEAT_RE
+fn find_non_bracketed_feat(title: &str, rules: &FeatRules) -> Option<usize> {
+    rules
+        .unbracketed_re
+        .as_ref()?
         .captures(title)
-        .and_then(|caps| caps.get(1))
+        .and_then(|caps| caps.name("word").or_else(|| caps.name("space")))
         .map(|m| m.start())
 }
 
 /// Given some content (either from inside a bracket or not) that starts with a feature keyword,
 /// remove that keyword (choosing the longest matching one) and any immediately following period.
-fn remove_keyword_from_content<'a>(content: &'a str, keywords: &[&str]) -> &'a str {
+fn remove_keyword_from_content<'a>(content: &'a str, keywords: &[FeatKeyword]) -> &'a str {
     let trimmed = content.trim();
     let lower = trimmed.to_lowercase();
-    if let Some(&kw) = keywords
+    if let Some(kw) = keywords
         .iter()
-        .filter(|&&k| lower.starts_with(k))
-        .max_by_key(|k| k.len())
+        .filter(|k| k.starts(&lower))
+        .max_by_key(|k| k.keyword.len())
     {
-        let after = trimmed.get(kw.len()..).unwrap_or("").trim_start();
+        let after = trimmed.get(kw.keyword.len()..).unwrap_or("").trim_start();
         if let Some(rest) = after.strip_prefix('.') {
             rest.trim_start()
         } else {
@@ -136,10 +429,53 @@ fn remove_keyword_from_content<'a>(content: &'a str, keywords: &[&str]) -> &'a s
     }
 }
 
-fn split_artists(artists_str: &str) -> Vec<String> {
-    artists_str
-        .split(',')
-        .flat_map(|s| s.split(" and ").flat_map(|s| s.split(" & ")))
+const ARTIST_SEPARATORS: &[&str] = &[",", " and ", " & "];
+
+/// Returns the length of the protected artist name starting at `i` in `s`, if there is one.
+fn protected_artist_at(s: &str, i: usize, protected_artists: &[String]) -> Option<usize> {
+    if s[..i]
+        .chars()
+        .next_back()
+        .is_some_and(char::is_alphanumeric)
+    {
+        return None;
+    }
+    protected_artists
+        .iter()
+        .filter(|name| {
+            s.get(i..i + name.len())
+                .is_some_and(|candidate| candidate.to_lowercase() == **name)
+                && !s[i + name.len()..]
+                    .chars()
+                    .next()
+                    .is_some_and(char::is_alphanumeric)
+        })
+        .map(String::len)
+        .max()
+}
+
+fn split_artists(artists_str: &str, protected_artists: &[String]) -> Vec<String> {
+    let mut artists = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < artists_str.len() {
+        if let Some(len) = protected_artist_at(artists_str, i, protected_artists) {
+            i += len;
+        } else if let Some(sep) = ARTIST_SEPARATORS
+            .iter()
+            .find(|sep| artists_str[i..].starts_with(**sep))
+        {
+            artists.push(&artists_str[start..i]);
+            i += sep.len();
+            start = i;
+        } else {
+            i += artists_str[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    artists.push(&artists_str[start..]);
+
+    artists
+        .into_iter()
         .map(|s| s.trim())
         .filter(|s| !s.is_empty())
         .map(String::from)
@@ -158,7 +494,7 @@ mod tests {
             featured_artists: Vec::new(),
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -169,7 +505,7 @@ mod tests {
             featured_artists: Vec::new(),
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -180,7 +516,7 @@ mod tests {
             featured_artists: vec!["Foo Bar".to_owned()],
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -191,7 +527,7 @@ mod tests {
             featured_artists: vec!["Foo Bar".to_owned(), "Baz Qux".to_owned()],
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -202,7 +538,7 @@ mod tests {
             featured_artists: vec!["Foo Bar".to_owned(), "Baz Qux".to_owned()],
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -217,7 +553,7 @@ mod tests {
             ],
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -232,7 +568,7 @@ mod tests {
             ],
             original_title: given.clone(),
         };
-        assert_eq!(extract_feat(&given), expected);
+        assert_eq!(extract_feat(&given, &FeatRules::default()), expected);
     }
 
     #[test]
@@ -246,6 +582,108 @@ mod tests {
             ],
             original_title: given.to_string(),
         };
-        assert_eq!(extract_feat(given), expected);
+        assert_eq!(extract_feat(given, &FeatRules::default()), expected);
+    }
+
+    fn multilingual_rules() -> FeatRules {
+        FeatRules::new(
+            "feat,ft,with:bracket,avec,part.,x:space"
+                .split(',')
+                .map(|k| k.parse().unwrap())
+                .collect(),
+            &[],
+        )
+    }
+
+    #[test]
+    fn test_extract_feat_bracket_boundary() {
+        let rules = multilingual_rules();
+        assert!(extract_feat("Dance with Me", &rules)
+            .featured_artists
+            .is_empty());
+        assert_eq!(
+            extract_feat("Dance (with Foo Bar)", &rules).featured_artists,
+            vec!["Foo Bar".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_extract_feat_space_boundary() {
+        let rules = multilingual_rules();
+        assert!(extract_feat("Max Power", &rules)
+            .featured_artists
+            .is_empty());
+        let feat = extract_feat("Chanson avec Foo", &rules);
+        assert_eq!(feat.title, "Chanson");
+        assert_eq!(feat.featured_artists, vec!["Foo".to_owned()]);
+        let feat = extract_feat("Foo x Bar", &rules);
+        assert_eq!(feat.title, "Foo");
+        assert_eq!(feat.featured_artists, vec!["Bar".to_owned()]);
+    }
+
+    #[test]
+    fn test_feat_keyword_parse() {
+        assert!("x:nowhere".parse::<FeatKeyword>().is_err());
+        assert_eq!(
+            "Part.".parse::<FeatKeyword>().unwrap(),
+            FeatKeyword {
+                keyword: "part.".to_owned(),
+                boundary: Boundary::Word,
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_feat_protected_artists() {
+        let protected = load_protected_artists(None, true).unwrap();
+        let rules = FeatRules::new(
+            DEFAULT_FEAT_KEYWORDS
+                .split(',')
+                .map(|k| k.parse().unwrap())
+                .collect(),
+            &protected,
+        );
+        let given = "Song (feat. Earth, Wind & Fire, simon & garfunkel and Foo)";
+        assert_eq!(
+            extract_feat(given, &rules).featured_artists,
+            vec![
+                "Earth, Wind & Fire".to_owned(),
+                "simon & garfunkel".to_owned(),
+                "Foo".to_owned(),
+            ]
+        );
+        // Only whole names are protected.
+        assert_eq!(
+            split_artists("Bob & Sam & Daveed", &rules.protected_artists),
+            vec!["Bob".to_owned(), "Sam".to_owned(), "Daveed".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_parse_remixer() {
+        assert_eq!(parse_remixer("Foo Bar Remix"), Some("Foo Bar".to_owned()));
+        assert_eq!(parse_remixer("Foo's remix"), Some("Foo".to_owned()));
+        assert_eq!(parse_remixer("Remixed by Foo"), Some("Foo".to_owned()));
+        assert_eq!(parse_remixer("Extended Radio Remix"), None);
+        assert_eq!(parse_remixer("2015 Remix"), None);
+        assert_eq!(parse_remixer("Stereo Remix"), None);
+        assert_eq!(parse_remixer("Mono Remix"), None);
+        assert_eq!(parse_remixer("New Remastered Remix"), None);
+        assert_eq!(parse_remixer("Radio Edit"), None);
+    }
+
+    #[test]
+    fn test_extract_producers() {
+        assert_eq!(
+            extract_producers("Song (prod. Foo) [Produced by Bar & Baz]"),
+            (
+                "Song".to_owned(),
+                vec!["Foo".to_owned(), "Bar & Baz".to_owned()]
+            )
+        );
+        assert_eq!(
+            extract_producers("Song (Product Placement)"),
+            ("Song (Product Placement)".to_owned(), Vec::new())
+        );
     }
 }
@@ -1,22 +1,84 @@
-use crate::track::feat::{extract_feat, TrackFeat};
+use crate::atomic;
+use crate::track::backup;
+use crate::track::date::fix_dates;
+use crate::track::feat::{
+    extract_feat, extract_producers, parse_remixer, FeatBrackets, FeatRules, FeatStyle, TrackFeat,
+};
+use crate::track::genre::{clean_genres, GenreMap};
+use crate::track::number;
+use crate::track::qualifier::{extract_qualifiers, QualifierPlacement};
+use crate::track::stray::{self, StrayTagMode};
+use crate::track::version::{convert_frames, target_version};
+use crate::track::writer::write_tag;
 use crate::track::Track;
 use anyhow::{bail, Result};
 use cow_utils::CowUtils;
-use id3::{Tag, TagLike, Version};
+use id3::frame::{Content, ExtendedText, InvolvedPeopleList, InvolvedPeopleListItem};
+use id3::{Frame, Tag, TagLike, Version};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+const ARTISTS_DESCRIPTION: &str = "ARTISTS";
+const PRODUCER: &str = "producer";
 
 static MULTI_WS_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[ \t]+").expect("BUG: Invalid regex"));
 
-pub fn run_fixers(track: &mut Track, dry_run: bool) -> Result<bool> {
+/// Settings controlling how fixes are applied and written out.
+pub struct FixOptions {
+    pub dry_run: bool,
+    /// Restore the file's original mtime and atime after writing the tag.
+    pub preserve_times: bool,
+    /// The ID3v2 version that tags are written as, if they should be converted.
+    pub id3_version: Option<Version>,
+    /// Where to save the original tag before it's first changed, if anywhere.
+    pub backup_dir: Option<PathBuf>,
+    /// What to do with ID3v1, APEv2, and duplicate ID3v2 tags.
+    pub stray_tags: StrayTagMode,
+    /// Which keywords introduce featured artists.
+    pub feat: FeatRules,
+    /// How featured artists are written into titles.
+    pub feat_style: FeatStyle,
+    /// Where version qualifiers like "Radio Edit" are written.
+    pub qualifiers: QualifierPlacement,
+    /// Canonical names for genres.
+    pub genre_map: GenreMap,
+    /// Rewrite dates in a canonical form.
+    pub fix_dates: bool,
+    /// Change dates and genres which disagree with the rest of their album, rather than only
+    /// reporting them.
+    pub fix_album_dates_genres: bool,
+}
+
+/// What `fix_tags` changed about a track's tag.
+pub struct TagFixes {
+    pub changed: bool,
+    /// Problems which were found, but deliberately left alone.
+    pub warnings: Vec<String>,
+}
+
+/// What `write_fixes` changed about a track.
+pub struct FixReport {
+    /// Whether the ID3v2 tag was changed.
+    pub tags_changed: bool,
+    /// The stray tags which were removed or synchronised.
+    pub stray_tags: Vec<&'static str>,
+}
+
+/// Applies all of the per-track fixes to `track`'s tag in memory. Nothing is written until
+/// `write_fixes`, so that album-level fixes can be applied in between.
+pub fn fix_tags(track: &mut Track, opts: &FixOptions) -> Result<TagFixes> {
     let tags = &mut track.tag;
 
     fixer_is_blacklisted(tags)?;
 
-    let new_title = fix_title(tags.title(), tags.artist());
-    let new_artist = fix_artist(tags.artist());
+    let new_title = fix_title(tags.title(), tags.artist(), &opts.feat, &opts.feat_style);
+    let new_artist = fix_artist(tags.artist(), &opts.feat);
     let new_album = fix_album(tags.album());
     let mut changed = false;
+    let mut warnings = Vec::new();
 
     if let Some(new_artist) = new_artist {
         changed = true;
@@ -30,12 +92,59 @@ pub fn run_fixers(track: &mut Track, dry_run: bool) -> Result<bool> {
         changed = true;
         tags.set_album(&new_album);
     }
+    let version = target_version(opts.id3_version, tags);
+    // Dates are parsed first, since values like "c. 1999" would otherwise be lost in conversion.
+    if opts.fix_dates && fix_dates(tags, version, &mut warnings) {
+        changed = true;
+    }
+    // Convert even if nothing else changed, so the whole library ends up on the requested version.
+    // This comes before the fixers below, since they write frames specific to the target version.
+    if opts.id3_version.is_some() && fix_version(tags, version) {
+        changed = true;
+    }
+    if fix_credits(tags, &opts.feat, version) {
+        changed = true;
+    }
+    if fix_genre(tags, &opts.genre_map, version) {
+        changed = true;
+    }
+    if fix_numbers(tags) {
+        changed = true;
+    }
+    if fix_subtitle(tags, &opts.feat, opts.qualifiers) {
+        changed = true;
+    }
+    if fix_artists_frame(tags, &opts.feat, version) {
+        changed = true;
+    }
 
-    if !dry_run && changed {
-        tags.write_to_path(&track.path, Version::Id3v24)?;
+    Ok(TagFixes { changed, warnings })
+}
+
+/// Writes `track`'s tag if `changed`, and deals with any stray tags in the file.
+pub fn write_fixes(track: &Track, changed: bool, opts: &FixOptions) -> Result<FixReport> {
+    let tags = &track.tag;
+    let strays = stray::check(&track.path, tags, opts.stray_tags)?;
+
+    if !opts.dry_run && (changed || !strays.is_empty()) {
+        if let Some(backup_dir) = &opts.backup_dir {
+            backup::save(&track.path, backup_dir)?;
+        }
+        let meta = fs::metadata(&track.path)?;
+        // Must come first, since it relies on the file layout from before the tag was rewritten.
+        strays.apply(&track.path)?;
+        if changed {
+            write_tag(tags, &track.path, target_version(opts.id3_version, tags))?;
+        }
+        if opts.preserve_times {
+            atomic::copy_times(&meta, &track.path)?;
+        }
     }
 
-    Ok(changed)
+    Ok(FixReport {
+        tags_changed: changed,
+        stray_tags: strays.changes,
+    })
 }
 
 // False positive: https://github.com/rust-lang/rust-clippy/issues/12444
@@ -54,9 +163,9 @@ fn normalise_field(field: &str) -> String {
         .to_string()
 }
 
-fn fix_artist(old_artist: Option<&str>) -> Option<String> {
+pub fn fix_artist(old_artist: Option<&str>, feat: &FeatRules) -> Option<String> {
     let field = normalise_field(old_artist.unwrap_or_default());
-    let artist = extract_feat(&field);
+    let artist = extract_feat(&field, feat);
     if artist.title == artist.original_title {
         None
     } else {
@@ -75,46 +184,302 @@ fn fix_album(old_album: Option<&str>) -> Option<String> {
     }
 }
 
-fn fix_title(old_title: Option<&str>, old_artist: Option<&str>) -> Option<String> {
+fn fix_title(
+    old_title: Option<&str>,
+    old_artist: Option<&str>,
+    feat: &FeatRules,
+    style: &FeatStyle,
+) -> Option<String> {
     let old_title = old_title?;
-    let title_feat = extract_feat(old_title);
-    let artist_feat = extract_feat(old_artist.unwrap_or_default());
-    let new_title = make_title(&title_feat, artist_feat);
+    // Qualifiers come out first, since they can follow an unbracketed feat clause.
+    let (base_title, qualifiers) = extract_qualifiers(old_title, feat);
+    let (base_title, producers) = extract_producers(&base_title);
+    let title_feat = extract_feat(&base_title, feat);
+    let artist_feat = extract_feat(old_artist.unwrap_or_default(), feat);
+    let new_title = make_title(&title_feat, artist_feat, &qualifiers, &producers, style);
 
-    if new_title == title_feat.original_title {
+    if new_title == old_title {
         None
     } else {
         Some(new_title)
     }
 }
 
-fn make_title(title: &TrackFeat, artist: TrackFeat) -> String {
+fn make_title(
+    title: &TrackFeat,
+    artist: TrackFeat,
+    qualifiers: &[String],
+    producers: &[String],
+    style: &FeatStyle,
+) -> String {
     let mut featured_artists = title.featured_artists.clone();
     featured_artists.extend(artist.featured_artists);
+    let featured_artists = dedup_featured_artists(featured_artists, &artist.title);
 
-    let mut new_title = title.title.clone();
+    // The feat section is normalised separately, since normalisation would turn square brackets
+    // into round ones.
+    let mut new_title = normalise_field(&title.title);
     if !featured_artists.is_empty() {
-        let feat_artists_string = make_feat_string(&featured_artists);
-        let feat_string = format!(" (feat. {feat_artists_string})");
+        let featured_artists: Vec<_> = featured_artists
+            .iter()
+            .map(|a| normalise_field(a))
+            .collect();
+        let feat_string = format!(
+            "{} {}",
+            style.keyword,
+            make_feat_string(&featured_artists, style)
+        );
+        let feat_string = match style.brackets {
+            FeatBrackets::Round => format!("({feat_string})"),
+            FeatBrackets::Square => format!("[{feat_string}]"),
+            FeatBrackets::Bare => feat_string,
+        };
+        if !new_title.is_empty() {
+            new_title.push(' ');
+        }
         new_title.push_str(&feat_string);
     }
+    for qualifier in qualifiers {
+        let qualifier = match parse_remixer(qualifier) {
+            Some(remixer) => format!("{remixer} Remix"),
+            None => qualifier.clone(),
+        };
+        new_title.push_str(&format!(" ({})", normalise_field(&qualifier)));
+    }
+    for producer in producers {
+        new_title.push_str(&format!(" (prod. {})", normalise_field(producer)));
+    }
+
+    new_title.trim_start().to_owned()
+}
+
+/// Joins the values of a multi-valued text frame. ID3v2.3 has no multi-valued frames, so use the
+/// conventional "/" separator there instead.
+fn join_values(values: &[String], version: Version) -> String {
+    let separator = if version == Version::Id3v24 {
+        "\0"
+    } else {
+        "/"
+    };
+    values.join(separator)
+}
+
+/// Writes remixer and producer credits from the (already fixed) title into TPE4 and the
+/// involved people list, so that they're searchable rather than only being part of the title.
+fn fix_credits(tags: &mut Tag, feat: &FeatRules, version: Version) -> bool {
+    let Some(title) = tags.title() else {
+        return false;
+    };
+    let (base_title, qualifiers) = extract_qualifiers(title, feat);
+    let remixers: Vec<_> = qualifiers
+        .iter()
+        .filter_map(|q| parse_remixer(q))
+        .flat_map(|r| feat.split_artists(&r))
+        .collect();
+    let producers: Vec<_> = extract_producers(&base_title)
+        .1
+        .iter()
+        .flat_map(|p| feat.split_artists(p))
+        .collect();
+
+    let mut changed = false;
+    if !remixers.is_empty() {
+        let value = join_values(&remixers, version);
+        if tags.get("TPE4").and_then(|f| f.content().text()) != Some(value.as_str()) {
+            tags.set_text("TPE4", value);
+            changed = true;
+        }
+    }
+    if !producers.is_empty() && fix_producers(tags, &producers, version) {
+        changed = true;
+    }
+    changed
+}
+
+/// Replaces the producers in the involved people list (TIPL, or IPLS in ID3v2.3), keeping any
+/// other roles.
+fn fix_producers(tags: &mut Tag, producers: &[String], version: Version) -> bool {
+    let id = if version == Version::Id3v24 {
+        "TIPL"
+    } else {
+        "IPLS"
+    };
+    let mut items = tags
+        .get(id)
+        .and_then(|f| f.content().involved_people_list())
+        .map(|l| l.items.clone())
+        .unwrap_or_default();
+    let is_producer =
+        |item: &InvolvedPeopleListItem| item.involvement.eq_ignore_ascii_case(PRODUCER);
+
+    let existing: Vec<_> = items
+        .iter()
+        .filter(|i| is_producer(i))
+        .map(|i| i.involvee.as_str())
+        .collect();
+    if existing == producers {
+        return false;
+    }
+
+    items.retain(|i| !is_producer(i));
+    items.extend(producers.iter().map(|p| InvolvedPeopleListItem {
+        involvement: PRODUCER.to_owned(),
+        involvee: p.clone(),
+    }));
+    tags.remove(id);
+    tags.add_frame(Frame::with_content(
+        id,
+        Content::InvolvedPeopleList(InvolvedPeopleList { items }),
+    ));
+    true
+}
+
+/// Cleans up the genre (TCON), writing multiple genres as a multi-valued frame.
+fn fix_genre(tags: &mut Tag, map: &GenreMap, version: Version) -> bool {
+    let Some(old) = tags.genre() else {
+        return false;
+    };
+    let values: Vec<_> = old.split('\0').collect();
+    let genres = clean_genres(&values, map);
+    if genres.is_empty() {
+        tags.remove("TCON");
+        return true;
+    }
+    let new = join_values(&genres, version);
+    if new == old {
+        return false;
+    }
+    tags.set_genre(new);
+    true
+}
+
+/// Normalises the track (TRCK) and disc (TPOS) numbers, like "03" to "3".
+fn fix_numbers(tags: &mut Tag) -> bool {
+    let mut changed = false;
+    for id in ["TRCK", "TPOS"] {
+        let new = tags
+            .get(id)
+            .and_then(|f| f.content().text())
+            .and_then(number::normalise);
+        if let Some(new) = new {
+            tags.set_text(id, new);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Copies or moves the version qualifiers at the end of the (already fixed) title into the
+/// subtitle frame.
+fn fix_subtitle(tags: &mut Tag, feat: &FeatRules, placement: QualifierPlacement) -> bool {
+    if placement == QualifierPlacement::Title {
+        return false;
+    }
+    let Some(title) = tags.title() else {
+        return false;
+    };
+    let (base_title, qualifiers) = extract_qualifiers(title, feat);
+    if qualifiers.is_empty() {
+        return false;
+    }
+
+    let mut changed = false;
+    let subtitle = qualifiers.join(", ");
+    if tags.get("TIT3").and_then(|f| f.content().text()) != Some(subtitle.as_str()) {
+        tags.set_text("TIT3", subtitle);
+        changed = true;
+    }
+    if placement == QualifierPlacement::Subtitle {
+        tags.set_title(base_title);
+        changed = true;
+    }
+    changed
+}
 
-    normalise_field(&new_title)
+/// Compares artist names ignoring differences in case and whitespace.
+pub fn artist_key(artist: &str) -> String {
+    artist
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
 }
 
-fn make_feat_string(featured_artists: &[String]) -> String {
+/// Removes repeated featured artists and any which are really the primary artist, keeping the
+/// first spelling seen of each.
+fn dedup_featured_artists(featured_artists: Vec<String>, primary_artist: &str) -> Vec<String> {
+    let mut seen = HashSet::from([artist_key(primary_artist)]);
+    featured_artists
+        .into_iter()
+        .filter(|a| seen.insert(artist_key(a)))
+        .collect()
+}
+
+fn make_feat_string(featured_artists: &[String], style: &FeatStyle) -> String {
+    let conjunction = &style.conjunction;
     match featured_artists.len() {
         0 => String::new(),
         1 => featured_artists[0].clone(),
-        2 => format!("{} and {}", featured_artists[0], featured_artists[1]),
+        2 => format!(
+            "{} {conjunction} {}",
+            featured_artists[0], featured_artists[1]
+        ),
         _ => {
             let head = &featured_artists[..featured_artists.len() - 1];
             let last = &featured_artists[featured_artists.len() - 1];
-            format!("{}, and {}", head.join(", "), last)
+            let before_conjunction = if style.oxford_comma {
+                style.separator.trim_end()
+            } else {
+                ""
+            };
+            format!(
+                "{}{before_conjunction} {conjunction} {last}",
+                head.join(&style.separator)
+            )
         }
     }
 }
 
+/// Records the primary artist and all featured artists in a multi-valued TXXX:ARTISTS frame, so
+/// that players and scrobblers which understand multiple artists can still attribute the track
+/// once the featured artists only appear in the title.
+fn fix_artists_frame(tags: &mut Tag, feat: &FeatRules, version: Version) -> bool {
+    let Some(artist) = tags.artist() else {
+        return false;
+    };
+    let mut artists = vec![artist.to_owned()];
+    artists.extend(dedup_featured_artists(
+        extract_feat(tags.title().unwrap_or_default(), feat).featured_artists,
+        artist,
+    ));
+    if artists.len() < 2 {
+        return false;
+    }
+
+    let value = join_values(&artists, version);
+    let existing: Vec<_> = tags
+        .extended_texts()
+        .filter(|t| t.description == ARTISTS_DESCRIPTION)
+        .map(|t| t.value.as_str())
+        .collect();
+    if existing == [value.as_str()] {
+        return false;
+    }
+
+    tags.remove_extended_text(Some(ARTISTS_DESCRIPTION), None);
+    tags.add_frame(ExtendedText {
+        description: ARTISTS_DESCRIPTION.to_owned(),
+        value,
+    });
+    true
+}
+
+fn fix_version(tags: &mut Tag, version: Version) -> bool {
+    let before = tags.clone();
+    convert_frames(tags, version);
+    tags.version() != version || *tags != before
+}
+
 fn fixer_is_blacklisted(tags: &Tag) -> Result<()> {
     for comment in tags.comments() {
         if comment.text.contains("_NO_MACK") {
@@ -132,14 +497,14 @@ mod tests {
     fn test_fix_artist_no_feat() {
         let given = "Foo Bar";
         let expected = None;
-        assert_eq!(fix_artist(Some(given)), expected);
+        assert_eq!(fix_artist(Some(given), &FeatRules::default()), expected);
     }
 
     #[test]
     fn test_fix_artist_with_feat() {
         let given = "Foo Bar (feat. Baz Qux)";
         let expected = Some("Foo Bar".to_owned());
-        assert_eq!(fix_artist(Some(given)), expected);
+        assert_eq!(fix_artist(Some(given), &FeatRules::default()), expected);
     }
 
     #[test]
@@ -147,7 +512,15 @@ mod tests {
         let given_title = "Foo Bar";
         let given_artist = "Baz Qux";
         let expected = None;
-        assert_eq!(fix_title(Some(given_title), Some(given_artist)), expected);
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
     }
 
     #[test]
@@ -155,15 +528,31 @@ mod tests {
         let given_title = "Foo Bar (feat. Wibble Wobble)";
         let given_artist = "Baz Qux";
         let expected = None;
-        assert_eq!(fix_title(Some(given_title), Some(given_artist)), expected);
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
     }
 
     #[test]
     fn test_fix_title_with_title_feat_no_artist_feat_and_brackets() {
         let given_title = "Foo Bar (feat. Wibble Wobble) [Richard Stallman mix]";
         let given_artist = "Baz Qux";
-        let expected = Some("Foo Bar (Richard Stallman mix) (feat. Wibble Wobble)".to_owned());
-        assert_eq!(fix_title(Some(given_title), Some(given_artist)), expected);
+        let expected = Some("Foo Bar (feat. Wibble Wobble) (Richard Stallman mix)".to_owned());
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
     }
 
     #[test]
@@ -171,7 +560,15 @@ mod tests {
         let given_title = "Foo Bar";
         let given_artist = "Baz Qux feat. Fizz Buzz";
         let expected = Some("Foo Bar (feat. Fizz Buzz)".to_owned());
-        assert_eq!(fix_title(Some(given_title), Some(given_artist)), expected);
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
     }
 
     #[test]
@@ -179,7 +576,15 @@ mod tests {
         let given_title = "Foo Bar (feat. Wibble Wobble)";
         let given_artist = "Baz Qux feat. Fizz Buzz";
         let expected = Some("Foo Bar (feat. Wibble Wobble and Fizz Buzz)".to_owned());
-        assert_eq!(fix_title(Some(given_title), Some(given_artist)), expected);
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
     }
 
     #[test]
@@ -187,13 +592,188 @@ mod tests {
         let given_title = "Foo ‘Bar’ (feat. Wibble “Wabble” Wobble)";
         let given_artist = "Baz Qux";
         let expected = Some("Foo 'Bar' (feat. Wibble \"Wabble\" Wobble)".to_owned());
-        assert_eq!(fix_title(Some(given_title), Some(given_artist)), expected);
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fix_title_qualifiers_after_feat() {
+        let given_title = "Foo Bar [Live at Wembley] feat. Wibble Wobble - 2011 Remaster";
+        let expected =
+            Some("Foo Bar (feat. Wibble Wobble) (Live at Wembley) (2011 Remaster)".to_owned());
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                None,
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fix_title_credits() {
+        let given_title = "Foo Bar [prod. by Baz Qux] - Wibble's Remix";
+        let expected = Some("Foo Bar (Wibble Remix) (prod. Baz Qux)".to_owned());
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                None,
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
+
+        let given_title = "Foo Bar (Produced by Mix Master Mike) (2015 Remix)";
+        let expected = Some("Foo Bar (2015 Remix) (prod. Mix Master Mike)".to_owned());
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                None,
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fix_credits() {
+        let mut tag = Tag::new();
+        tag.set_title("Foo Bar (Wibble & Wobble Remix) (prod. Baz Qux)");
+        let feat = FeatRules::default();
+        assert!(fix_credits(&mut tag, &feat, Version::Id3v24));
+        assert_eq!(
+            tag.get("TPE4").and_then(|f| f.content().text()),
+            Some("Wibble\0Wobble")
+        );
+        let people = tag
+            .get("TIPL")
+            .and_then(|f| f.content().involved_people_list())
+            .unwrap();
+        assert_eq!(people.items.len(), 1);
+        assert_eq!(people.items[0].involvee, "Baz Qux");
+        assert!(!fix_credits(&mut tag, &feat, Version::Id3v24));
+    }
+
+    #[test]
+    fn test_fix_subtitle() {
+        let mut tag = Tag::new();
+        tag.set_title("Foo Bar (feat. Wibble Wobble) (Radio Edit)");
+        let feat = FeatRules::default();
+        assert!(fix_subtitle(&mut tag, &feat, QualifierPlacement::Subtitle));
+        assert_eq!(tag.title(), Some("Foo Bar (feat. Wibble Wobble)"));
+        assert_eq!(
+            tag.get("TIT3").and_then(|f| f.content().text()),
+            Some("Radio Edit")
+        );
+        assert!(!fix_subtitle(&mut tag, &feat, QualifierPlacement::Subtitle));
+    }
+
+    #[test]
+    fn test_fix_title_dedups_featured_artists() {
+        let given_title = "Foo Bar (feat. Wibble Wobble)";
+        let given_artist = "Baz Qux feat. wibble  WOBBLE, Fizz Buzz and Baz Qux";
+        let expected = Some("Foo Bar (feat. Wibble Wobble and Fizz Buzz)".to_owned());
+        assert_eq!(
+            fix_title(
+                Some(given_title),
+                Some(given_artist),
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fix_title_custom_style() {
+        let given_title = "Foo Bar (feat. Wibble Wobble, Fizz Buzz, and Baz Qux)";
+        let style = FeatStyle {
+            brackets: FeatBrackets::Square,
+            keyword: "ft.".to_owned(),
+            conjunction: "&".to_owned(),
+            oxford_comma: false,
+            ..FeatStyle::default()
+        };
+        let expected = Some("Foo Bar [ft. Wibble Wobble, Fizz Buzz & Baz Qux]".to_owned());
+        assert_eq!(
+            fix_title(Some(given_title), None, &FeatRules::default(), &style),
+            expected
+        );
+
+        let style = FeatStyle {
+            brackets: FeatBrackets::Bare,
+            ..style
+        };
+        let given_title = "Foo Bar [ft. Wibble Wobble & Baz Qux]";
+        let expected = Some("Foo Bar ft. Wibble Wobble & Baz Qux".to_owned());
+        assert_eq!(
+            fix_title(Some(given_title), None, &FeatRules::default(), &style),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fix_artists_frame() {
+        let mut tag = Tag::new();
+        tag.set_artist("Foo Bar");
+        tag.set_title("Baz (feat. Wibble Wobble and Fizz Buzz)");
+        assert!(fix_artists_frame(
+            &mut tag,
+            &FeatRules::default(),
+            Version::Id3v24
+        ));
+        let artists: Vec<_> = tag.extended_texts().map(|t| t.value.as_str()).collect();
+        assert_eq!(artists, ["Foo Bar\0Wibble Wobble\0Fizz Buzz"]);
+        assert!(!fix_artists_frame(
+            &mut tag,
+            &FeatRules::default(),
+            Version::Id3v24
+        ));
+
+        let mut tag = Tag::new();
+        tag.set_artist("Foo Bar");
+        tag.set_title("Baz");
+        assert!(!fix_artists_frame(
+            &mut tag,
+            &FeatRules::default(),
+            Version::Id3v24
+        ));
+    }
+
+    #[test]
+    fn test_fix_version_converts_unchanged_tag() {
+        let mut tag = Tag::with_version(Version::Id3v24);
+        tag.set_title("Foo Bar");
+        assert!(fix_version(&mut tag, Version::Id3v23));
+
+        let mut tag = Tag::with_version(Version::Id3v23);
+        tag.set_title("Foo Bar");
+        assert!(!fix_version(&mut tag, Version::Id3v23));
     }
 
     #[test]
     fn test_fix_whitespace() {
         let given = "    Foo Bar [feat.    Baz    Qux   ]    ";
         let expected = Some("Foo Bar (feat. Baz Qux)".to_owned());
-        assert_eq!(fix_title(Some(given), None), expected);
+        assert_eq!(
+            fix_title(
+                Some(given),
+                None,
+                &FeatRules::default(),
+                &FeatStyle::default()
+            ),
+            expected
+        );
     }
 }
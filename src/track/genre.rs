@@ -0,0 +1,170 @@
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// An ID3v1 genre reference at the start of a TCON value, like "(17)", or the ID3v2.3 "(RX)"
+/// (remix) and "(CR)" (cover) references.
+static REFERENCE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\((\d+|RX|CR)\)").expect("BUG: Invalid genre reference regex"));
+
+/// Separators between genres which have been put in one value, like "Rock; Pop". Others like "/"
+/// and "," are left alone, since they're part of plenty of genres like "Drum/Bass" or "Rhythm,
+/// Blues", but the genre map can split values on them by mapping to several genres.
+const GENRE_SEPARATORS: &[char] = &['\0', ';'];
+
+/// Canonical names for genres, keyed by `genre_key`.
+#[derive(Default)]
+pub struct GenreMap(HashMap<String, String>);
+
+/// Compares genres ignoring case and anything other than letters and digits, so that "Hip Hop",
+/// "hip-hop", and "hiphop" are the same.
+fn genre_key(genre: &str) -> String {
+    genre
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+impl GenreMap {
+    /// Returns the canonical name for `genre`, which is empty if it should be dropped.
+    fn canonical<'a>(&'a self, genre: &'a str) -> &'a str {
+        self.0.get(&genre_key(genre)).map_or(genre, String::as_str)
+    }
+}
+
+/// Reads genre mappings from `path`, one "from = to" pair per line, with blank lines and lines
+/// starting with "#" ignored. Leaving "to" empty drops the genre.
+pub fn load_genre_map(path: Option<&Path>) -> Result<GenreMap> {
+    let mut map = HashMap::new();
+    let Some(path) = path else {
+        return Ok(GenreMap(map));
+    };
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("can't read genre map from {}", path.display()))?;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((from, to)) = line.split_once('=') else {
+            bail!("{}:{}: expected \"from = to\"", path.display(), i + 1);
+        };
+        map.insert(genre_key(from), to.trim().to_owned());
+    }
+    Ok(GenreMap(map))
+}
+
+fn resolve_reference(reference: &str) -> Option<String> {
+    match reference {
+        "RX" => Some("Remix".to_owned()),
+        "CR" => Some("Cover".to_owned()),
+        _ => id3::v1::Tag {
+            genre_id: reference.parse().ok()?,
+            ..Default::default()
+        }
+        .genre()
+        .map(String::from),
+    }
+}
+
+/// Resolves ID3v1 genre references in a single TCON value. Text after the references refines the
+/// last of them, as in "(17)Rock", so it takes its place. "((" escapes a literal bracket.
+fn resolve_references(value: &str) -> Vec<String> {
+    let value = value.trim();
+    if let Some(id) = resolve_reference(value) {
+        return vec![id];
+    }
+
+    let mut genres = Vec::new();
+    let mut rest = value;
+    while let Some(caps) = REFERENCE_RE.captures(rest) {
+        let Some(genre) = resolve_reference(&caps[1]) else {
+            break;
+        };
+        genres.push(genre);
+        rest = &rest[caps[0].len()..];
+    }
+    let rest = rest
+        .strip_prefix('(')
+        .filter(|r| r.starts_with('('))
+        .unwrap_or(rest);
+    if !rest.trim().is_empty() {
+        genres.pop();
+        genres.push(rest.to_owned());
+    }
+    genres
+}
+
+/// Cleans up the values of a TCON frame: ID3v1 genre references are resolved, values holding
+/// several genres are split, and `map` is applied. A genre mapped to something like "Hip-Hop; Rap"
+/// is split into those genres, which are then mapped themselves. Duplicates are removed.
+pub fn clean_genres(values: &[&str], map: &GenreMap) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut genres = Vec::new();
+    for value in values.iter().flat_map(|v| resolve_references(v)) {
+        for part in value.split(GENRE_SEPARATORS) {
+            let part = part.split_whitespace().collect::<Vec<_>>().join(" ");
+            for genre in map.canonical(&part).split(GENRE_SEPARATORS) {
+                let genre = map.canonical(genre.trim()).trim();
+                if !genre.is_empty() && seen.insert(genre_key(genre)) {
+                    genres.push(genre.to_owned());
+                }
+            }
+        }
+    }
+    genres
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(values: &[&str], map: &[(&str, &str)]) -> Vec<String> {
+        let map = GenreMap(
+            map.iter()
+                .map(|(from, to)| (genre_key(from), (*to).to_owned()))
+                .collect(),
+        );
+        clean_genres(values, &map)
+    }
+
+    #[test]
+    fn test_clean_genres_references() {
+        assert_eq!(clean(&["(17)"], &[]), ["Rock"]);
+        assert_eq!(clean(&["(17)Rock"], &[]), ["Rock"]);
+        assert_eq!(clean(&["17"], &[]), ["Rock"]);
+        assert_eq!(clean(&["(17)(RX)"], &[]), ["Rock", "Remix"]);
+        assert_eq!(clean(&["((Foo)"], &[]), ["(Foo)"]);
+        assert_eq!(clean(&["(999)"], &[]), ["(999)"]);
+    }
+
+    #[test]
+    fn test_clean_genres_split_and_map() {
+        assert_eq!(clean(&["Rock; Pop\0rock"], &[]), ["Rock", "Pop"]);
+        assert_eq!(
+            clean(
+                &["Hip Hop/Rap; hiphop"],
+                &[("hip hop/rap", "Hip-Hop"), ("hiphop", "Hip-Hop")]
+            ),
+            ["Hip-Hop"]
+        );
+        assert_eq!(
+            clean(
+                &["Hip Hop/Rap"],
+                &[("hip hop/rap", "Hip-Hop; rap"), ("rap", "Rap")]
+            ),
+            ["Hip-Hop", "Rap"]
+        );
+        assert_eq!(clean(&["Rock", "Other"], &[("other", "")]), ["Rock"]);
+    }
+
+    #[test]
+    fn test_clean_genres_keeps_slashes_and_commas() {
+        assert_eq!(clean(&["Drum/Bass"], &[]), ["Drum/Bass"]);
+        assert_eq!(clean(&["Rhythm, Blues"], &[]), ["Rhythm, Blues"]);
+    }
+}
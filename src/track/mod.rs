@@ -1,7 +1,17 @@
+pub mod album;
+pub mod backup;
+pub mod date;
 pub mod feat;
 pub mod fixers;
+pub mod genre;
 pub mod loader;
+pub mod number;
+pub mod profile;
+pub mod qualifier;
 pub mod rename;
+pub mod stray;
+pub mod version;
+pub mod writer;
 
 pub use loader::get_track;
 
@@ -0,0 +1,75 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static NUMBER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^(\d+)(?:\s*(?:/|of)\s*(\d+))?$").expect("BUG: Invalid number regex")
+});
+static VINYL_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^([A-Za-z])\s*-?\s*(\d+)$").expect("BUG: Invalid vinyl regex"));
+
+/// A track (TRCK) or disc (TPOS) position, as written in the tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    /// A number, and possibly the total, as in "3/12" or "03".
+    Number(u32, Option<u32>),
+    /// A vinyl side and the track's number on it, as in "A1" or "B-2".
+    Vinyl(char, u32),
+}
+
+impl Position {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if let Some(caps) = NUMBER_RE.captures(text) {
+            let number = caps[1].parse().ok()?;
+            let total = caps.get(2).and_then(|t| t.as_str().parse().ok());
+            // A total of 0 is what some taggers write when they don't know it.
+            return Some(Self::Number(number, total.filter(|&t| t > 0)));
+        }
+        let caps = VINYL_RE.captures(text)?;
+        let side = caps[1].chars().next()?.to_ascii_uppercase();
+        Some(Self::Vinyl(side, caps[2].parse().ok()?))
+    }
+
+    /// Returns how this is written in a tag, or None for vinyl positions, which can only be turned
+    /// into numbers with the rest of the album.
+    pub fn canonical(&self) -> Option<String> {
+        match *self {
+            Self::Number(number, Some(total)) => Some(format!("{number}/{total}")),
+            Self::Number(number, None) => Some(number.to_string()),
+            Self::Vinyl(..) => None,
+        }
+    }
+}
+
+/// Rewrites a TRCK or TPOS value like "03" or "3 of 12" as "3" or "3/12". Returns None if it's
+/// already in that form, or can't be rewritten.
+pub fn normalise(text: &str) -> Option<String> {
+    Position::parse(text)?
+        .canonical()
+        .filter(|canonical| canonical != text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Position::parse("3/12"), Some(Position::Number(3, Some(12))));
+        assert_eq!(
+            Position::parse(" 03 of 12"),
+            Some(Position::Number(3, Some(12)))
+        );
+        assert_eq!(Position::parse("3/0"), Some(Position::Number(3, None)));
+        assert_eq!(Position::parse("b2"), Some(Position::Vinyl('B', 2)));
+        assert_eq!(Position::parse("Side A"), None);
+    }
+
+    #[test]
+    fn test_normalise() {
+        assert_eq!(normalise("03"), Some("3".to_owned()));
+        assert_eq!(normalise("03/012"), Some("3/12".to_owned()));
+        assert_eq!(normalise("3/12"), None);
+        assert_eq!(normalise("A1"), None);
+    }
+}
@@ -0,0 +1,131 @@
+use clap::ValueEnum;
+
+/// Illegal characters for Windows filenames, except for / and \ which are path separators and
+/// handled by `components()`.
+const WINDOWS_ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+/// Android's emulated storage rejects the same set as FAT, even when backed by ext4/f2fs.
+const ANDROID_ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The filesystem (or consumer) that the output paths are intended for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FsProfile {
+    /// mack's original rules: characters Windows rejects are replaced, but nothing else is
+    Legacy,
+    /// ext4, btrfs, xfs, and friends
+    Posix,
+    /// NTFS as seen through Windows
+    Windows,
+    /// FAT32 with long file names, as commonly found on SD cards
+    Fat32,
+    /// exFAT, as commonly found on phones and larger SD cards
+    Exfat,
+    /// Android shared storage
+    Android,
+    /// POSIX rules, but only allowing ASCII characters
+    Ascii,
+}
+
+/// How the length of a path (or part of one) is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LenUnit {
+    /// UTF-8 bytes, as used by most POSIX filesystems
+    Bytes,
+    /// Unicode scalar values
+    Chars,
+    /// UTF-16 code units, as used by Windows and FAT/exFAT
+    Utf16,
+}
+
+impl LenUnit {
+    pub fn len(self, s: &str) -> usize {
+        match self {
+            Self::Bytes => s.len(),
+            Self::Chars => s.chars().count(),
+            Self::Utf16 => s.encode_utf16().count(),
+        }
+    }
+}
+
+/// The constraints imposed by a given `FsProfile`.
+#[derive(Clone, Copy, Debug)]
+pub struct FsRules {
+    pub illegal_chars: &'static [char],
+    pub illegal_control_chars: bool,
+    pub reserved_names: &'static [&'static str],
+    pub len_unit: LenUnit,
+    pub max_component_len: usize,
+    pub max_path_len: usize,
+    pub case_insensitive: bool,
+    pub ascii_only: bool,
+}
+
+impl FsProfile {
+    pub const fn rules(self) -> FsRules {
+        const POSIX: FsRules = FsRules {
+            illegal_chars: &[],
+            illegal_control_chars: false,
+            reserved_names: &[],
+            len_unit: LenUnit::Bytes,
+            max_component_len: 255,
+            max_path_len: 4096,
+            case_insensitive: false,
+            ascii_only: false,
+        };
+        const WINDOWS: FsRules = FsRules {
+            illegal_chars: WINDOWS_ILLEGAL_CHARS,
+            illegal_control_chars: true,
+            reserved_names: WINDOWS_RESERVED_NAMES,
+            len_unit: LenUnit::Utf16,
+            max_component_len: 255,
+            max_path_len: 260,
+            case_insensitive: true,
+            ascii_only: false,
+        };
+
+        match self {
+            Self::Legacy => FsRules {
+                illegal_chars: WINDOWS_ILLEGAL_CHARS,
+                ..POSIX
+            },
+            Self::Posix => POSIX,
+            // Long file names on FAT32 have the same restrictions as NTFS does through Win32.
+            Self::Windows | Self::Fat32 => WINDOWS,
+            Self::Exfat => FsRules {
+                reserved_names: &[],
+                max_path_len: usize::MAX,
+                ..WINDOWS
+            },
+            Self::Android => FsRules {
+                illegal_chars: ANDROID_ILLEGAL_CHARS,
+                illegal_control_chars: true,
+                case_insensitive: true,
+                ..POSIX
+            },
+            Self::Ascii => FsRules {
+                ascii_only: true,
+                ..POSIX
+            },
+        }
+    }
+}
+
+impl FsRules {
+    pub fn is_illegal(&self, c: char) -> bool {
+        self.illegal_chars.contains(&c)
+            || (self.illegal_control_chars && c.is_control())
+            || (self.ascii_only && !c.is_ascii())
+    }
+
+    /// Reserved names apply to the part before the first dot, so "CON.mp3" is also reserved.
+    pub fn is_reserved(&self, component: &str) -> bool {
+        let stem = component.split('.').next().unwrap_or_default().trim_end();
+        self.reserved_names
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(stem))
+    }
+}
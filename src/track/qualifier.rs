@@ -0,0 +1,201 @@
+use crate::track::feat::{is_producer_credit, FeatRules};
+use clap::ValueEnum;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+/// Words which mark part of a title as describing which version of a track this is, rather than
+/// being part of its name.
+static QUALIFIER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)\b(?:remaster(?:ed)?|edit|live|acoustic|demo|remix|mix|version|edition|extended|instrumental|mono|stereo|unplugged|re-?recorded|reprise|dub|a ?cappella|karaoke|bonus track)\b",
+    )
+    .expect("BUG: Invalid qualifier regex")
+});
+
+/// Whole qualifier phrases which can follow a dash, as in "Song - 2011 Remaster". These have to
+/// match the entire suffix, since words like "Live" are common in ordinary titles too.
+static DASH_QUALIFIER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?ix)^(?:
+            (?:\d{4}\s)?(?:digital(?:ly)?\s)?remaster(?:ed)?(?:\s\d{4})?(?:\sversion)?
+            |(?:radio|single|album|original|extended|clean|explicit)\s(?:edit|version|mix)
+            |live(?:\s(?:at|from|in)\s.+)?
+            |.+\s(?:re)?mix
+            |(?:acoustic|instrumental|mono|stereo|unplugged|demo|remix|reprise|karaoke
+                |a\s?cappella)(?:\sversion)?
+            |re-?recorded(?:\s\d{4})?
+            |bonus\strack
+        )$",
+    )
+    .expect("BUG: Invalid dash qualifier regex")
+});
+
+/// Separators before an unbracketed qualifier, as in "Song - 2011 Remaster".
+const DASH_SEPARATORS: &[&str] = &[" - ", " – ", " — "];
+
+/// Where version qualifiers like "Radio Edit" end up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum QualifierPlacement {
+    /// At the end of the title, in brackets
+    Title,
+    /// Only in the subtitle (TIT3) frame
+    Subtitle,
+    /// Both at the end of the title and in the subtitle frame
+    Both,
+}
+
+fn is_qualifier(text: &str) -> bool {
+    QUALIFIER_RE.is_match(text)
+}
+
+fn is_dash_qualifier(text: &str) -> bool {
+    DASH_QUALIFIER_RE.is_match(text)
+}
+
+/// Returns the byte ranges (including the brackets themselves) of top level bracketed sections
+/// in `title`, using '(' or '['.
+pub fn bracketed_sections(title: &str) -> Vec<(usize, usize)> {
+    let mut sections = Vec::new();
+    let mut chars = title.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        let closing = match ch {
+            '(' => ')',
+            '[' => ']',
+            _ => continue,
+        };
+        let mut depth = 1;
+        for (j, ch2) in chars.by_ref() {
+            if ch2 == ch {
+                depth += 1;
+            } else if ch2 == closing {
+                depth -= 1;
+                if depth == 0 {
+                    sections.push((i, j + closing.len_utf8()));
+                    break;
+                }
+            }
+        }
+    }
+    sections
+}
+
+/// Splits version qualifiers like "(Radio Edit)", "[Live at Wembley]", or "- 2011 Remaster" out
+/// of `title`. Returns the rest of the title, and the qualifiers without brackets or dashes in the
+/// order they appeared.
+///
+/// Bracketed sections only need to mention a qualifier, but a suffix after a dash has to be one
+/// in its entirety, so that titles like "Song - Live Your Life" are left alone. Feat clauses and
+/// producer credits are left alone too, even if an artist is called something like "Mono" or "Mix
+/// Master Mike".
+pub fn extract_qualifiers(title: &str, feat: &FeatRules) -> (String, Vec<String>) {
+    let mut rest = title.trim();
+    let mut dash_qualifiers = Vec::new();
+    while let Some((pos, sep)) = DASH_SEPARATORS
+        .iter()
+        .filter_map(|sep| rest.rfind(sep).map(|pos| (pos, sep)))
+        .max_by_key(|(pos, _)| *pos)
+    {
+        let suffix = rest[pos + sep.len()..].trim();
+        let is_credit = feat.is_feat_section(suffix) || is_producer_credit(suffix);
+        if !is_dash_qualifier(suffix) || is_credit {
+            break;
+        }
+        dash_qualifiers.insert(0, suffix.to_owned());
+        rest = rest[..pos].trim_end();
+    }
+
+    let mut qualifiers = Vec::new();
+    let mut base = String::new();
+    let mut last_end = 0;
+    for (start, end) in bracketed_sections(rest) {
+        let content = &rest[start + 1..end - 1];
+        if is_qualifier(content) && !feat.is_feat_section(content) && !is_producer_credit(content) {
+            qualifiers.push(content.trim().to_owned());
+            base.push_str(&rest[last_end..start]);
+            last_end = end;
+        }
+    }
+    base.push_str(&rest[last_end..]);
+    qualifiers.extend(dash_qualifiers);
+
+    let base = base.split_whitespace().collect::<Vec<_>>().join(" ");
+    (base, qualifiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(title: &str) -> (String, Vec<String>) {
+        extract_qualifiers(title, &FeatRules::default())
+    }
+
+    #[test]
+    fn test_extract_qualifiers() {
+        assert_eq!(
+            extract("Song - 2011 Remaster"),
+            ("Song".to_owned(), vec!["2011 Remaster".to_owned()])
+        );
+        assert_eq!(
+            extract("Song [Live at Wembley] (Radio Edit)"),
+            (
+                "Song".to_owned(),
+                vec!["Live at Wembley".to_owned(), "Radio Edit".to_owned()]
+            )
+        );
+        assert_eq!(
+            extract("Song - Live at Wembley - Radio Edit"),
+            (
+                "Song".to_owned(),
+                vec!["Live at Wembley".to_owned(), "Radio Edit".to_owned()]
+            )
+        );
+        assert_eq!(
+            extract("Song (Part 2) - Mono"),
+            ("Song (Part 2)".to_owned(), vec!["Mono".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_extract_qualifiers_none() {
+        assert_eq!(
+            extract("Olive - Part 2 (Interlude)"),
+            ("Olive - Part 2 (Interlude)".to_owned(), Vec::new())
+        );
+        assert_eq!(
+            extract("Song One - Live Your Life"),
+            ("Song One - Live Your Life".to_owned(), Vec::new())
+        );
+        assert_eq!(
+            extract("Song One - Mix Tape"),
+            ("Song One - Mix Tape".to_owned(), Vec::new())
+        );
+        assert_eq!(
+            extract("Song One - Special Edition Bonus"),
+            ("Song One - Special Edition Bonus".to_owned(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_extract_qualifiers_skips_producers() {
+        assert_eq!(
+            extract("Song (Produced by Mix Master Mike)"),
+            ("Song (Produced by Mix Master Mike)".to_owned(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_extract_qualifiers_skips_feat() {
+        assert_eq!(
+            extract("Song (feat. Mix Master Mike)"),
+            ("Song (feat. Mix Master Mike)".to_owned(), Vec::new())
+        );
+        assert_eq!(
+            extract("Song (feat. Mono) (Radio Edit)"),
+            (
+                "Song (feat. Mono)".to_owned(),
+                vec!["Radio Edit".to_owned()]
+            )
+        );
+    }
+}
@@ -1,26 +1,33 @@
+use crate::atomic;
+use crate::hash::short_hash;
+use crate::track::profile::{FsRules, LenUnit};
 use crate::track::Track;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use deunicode::deunicode_with_tofu;
 use funcfmt::{FormatPieces, Render};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use std::collections::{BTreeSet, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 #[cfg(target_family = "unix")]
 use libc::EXDEV as xdev_err;
 #[cfg(target_family = "windows")]
 use winapi::shared::winerror::ERROR_NOT_SAME_DEVICE as xdev_err;
 
-fn rename_creating_dirs(from: &PathBuf, to: &PathBuf) -> Result<()> {
+fn rename_creating_dirs(from: &Path, to: &Path) -> Result<()> {
     fs::create_dir_all(to.parent().context("Refusing to move to FS root")?)?;
 
-    // Trying to rename cross device? Just copy and unlink the old one
+    // Trying to rename cross device? Copy and only unlink the old one once the copy is known to
+    // be safely on disk.
     if let Err(err) = fs::rename(from, to) {
         #[allow(clippy::useless_conversion)] // Necessary for Windows only
         let xdev_err_cast = xdev_err.try_into()?;
         if err.raw_os_error() == Some(xdev_err_cast) {
-            fs::copy(from, to)?;
+            atomic::copy_verified(from, to)?;
             fs::remove_file(from)?;
         } else {
             Err(err)?;
@@ -29,90 +36,676 @@ fn rename_creating_dirs(from: &PathBuf, to: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// `String::truncate` will panic if not at a char boundary
-fn safe_truncate(s: &mut String, max_chars: usize) {
-    if let Some((idx, _)) = s.char_indices().nth(max_chars) {
-        s.truncate(idx);
+/// Renames `from` to `to` when they only differ by case, going via a temporary name since a direct
+/// rename is a no-op (or fails) on some case-insensitive filesystems. If we're killed in between,
+/// the next run finds the temporary name and finishes the rename.
+fn rename_case_only(from: &Path, to: &Path) -> Result<()> {
+    let tmp = atomic::temp_path_for(to);
+    fs::rename(from, &tmp)?;
+    if let Err(err) = fs::rename(&tmp, to) {
+        // Put things back how they were rather than leaving the temporary name around.
+        let _ = fs::rename(&tmp, from);
+        Err(err)?;
     }
+    Ok(())
+}
+
+/// Finds an entry in `dir` which has the same name as `name` apart from case.
+fn find_case_variant(dir: &Path, name: &OsStr) -> Option<OsString> {
+    let wanted = name.to_string_lossy().to_lowercase();
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|e| e.file_name())
+        .find(|n| n != name && n.to_string_lossy().to_lowercase() == wanted)
+}
+
+/// Whether `a` and `b` are the same file, even if they're spelled differently, as happens when
+/// only the case of a directory changes on a case-insensitive filesystem.
+#[cfg(target_family = "unix")]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Canonicalising resolves the case stored on disk on Windows, so this spots the same file under
+/// different case.
+#[cfg(target_family = "windows")]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Checks whether the directory `dir` is on a case-insensitive filesystem, by creating a temporary
+/// file and looking it up with different case. `dir` has to exist already, since its parent could
+/// be on a different filesystem.
+pub fn is_case_insensitive_fs(dir: &Path) -> Result<bool> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    let probe = dir.join(format!(".mack-probe-{}", std::process::id()));
+    fs::File::create(&probe)?;
+    let insensitive = dir
+        .join(format!(".MACK-PROBE-{}", std::process::id()))
+        .exists();
+    fs::remove_file(&probe)?;
+    Ok(insensitive)
 }
 
-// Arbitrary limit on path part without extension to try to avoid brushing against PATH_MAX. We
-// can't just check PATH_MAX and similar, because we also want to avoid issues when copying
-// elsewhere later.
+// Arbitrary limit on path part to try to avoid brushing against PATH_MAX. We can't just check
+// PATH_MAX and similar, because we also want to avoid issues when copying elsewhere later.
+pub const DEFAULT_MAX_PART_LEN: usize = 64;
+
 static MULTI_DOT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\.\.+").expect("BUG: Invalid regex"));
-// Illegal characters for Windows filenames, except for / and \ which are path separators and
-// handled by `components()`.
-static ILLEGAL_CHARS_RE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"[<>:"|?*]"#).expect("BUG: Invalid regex"));
-const MAX_PATH_PART_LEN: usize = 64;
-fn normalise_dirs(path_part: String) -> PathBuf {
-    let partial = PathBuf::from(path_part);
-    partial
-        .components()
-        .map(|c| {
-            let mut s = c
-                .as_os_str()
-                .to_os_string()
-                .into_string()
-                .expect("invalid path");
-            safe_truncate(&mut s, MAX_PATH_PART_LEN);
 
-            // Replace illegal characters with underscores.
-            s = ILLEGAL_CHARS_RE.replace_all(&s, "_").to_string();
+/// Settings controlling how rendered paths are turned into paths on disk.
+#[derive(Clone, Copy, Debug)]
+pub struct PathOptions {
+    pub rules: FsRules,
+    /// Romanise non-ASCII characters (e.g. kana, Cyrillic, accented Latin) instead of keeping them.
+    pub transliterate: bool,
+    /// Maximum length of each path component.
+    pub max_part_len: usize,
+    pub part_len_unit: LenUnit,
+    /// Count the extension towards `max_part_len` for the file name.
+    pub part_len_includes_ext: bool,
+    /// Cut long components at a word boundary where possible, rather than mid-word.
+    pub truncate_at_word: bool,
+}
 
-            // Trim leading/trailing whitespace, which can be problematic on some filesystems.
-            s = s.trim().to_string();
+impl PathOptions {
+    /// Whether a single component, followed by `suffix` (the extension, for file names), fits both
+    /// our own limit and the target filesystem's.
+    fn part_fits(&self, part: &str, suffix: &str) -> bool {
+        let full = format!("{part}{suffix}");
+        let own = if self.part_len_includes_ext {
+            &full
+        } else {
+            part
+        };
+        self.part_len_unit.len(own) <= self.max_part_len
+            && self.rules.len_unit.len(&full) <= self.rules.max_component_len
+    }
+}
 
-            // exfat normalises this and it confuses adb-sync and other tooling
-            s = MULTI_DOT_RE.replace_all(&s, ".").to_string();
+/// Returns the longest prefix of `s` for which `fits(prefix)` holds. With `at_word`, prefers to
+/// cut at a word boundary if one exists in the latter half of the prefix.
+fn truncate(s: &str, at_word: bool, fits: impl Fn(&str) -> bool) -> &str {
+    if fits(s) {
+        return s;
+    }
+    let end = s
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .take_while(|&idx| fits(&s[..idx]))
+        .last()
+        .unwrap_or(0);
+    match s[..end].rfind(char::is_whitespace) {
+        Some(ws) if at_word && ws >= end / 2 => &s[..ws],
+        _ => &s[..end],
+    }
+}
 
-            // Disallow leading dots to prevent creating hidden files/directories.
-            // Disallow trailing dots as they are invalid on Windows.
-            let s = s.trim_matches('.').to_string();
+/// Romanises `s` to ASCII, replacing anything without a known transliteration with `_`.
+fn transliterate(s: &str) -> String {
+    deunicode_with_tofu(s, "_")
+}
 
-            // If the component is now empty (e.g. it was just "."), use a placeholder.
-            if s.is_empty() {
-                "_".to_string()
+/// Replaces characters which can't appear in a path component on the target.
+fn replace_illegal(s: &str, opts: &PathOptions) -> String {
+    let s = if opts.transliterate {
+        transliterate(s)
+    } else {
+        s.to_string()
+    };
+    s.chars()
+        .map(|c| {
+            if c == '/' || c == '\\' || opts.rules.is_illegal(c) {
+                '_'
             } else {
-                s
+                c
             }
         })
         .collect()
 }
 
-fn add_extension(path: PathBuf, ext: impl AsRef<OsStr>) -> PathBuf {
-    let mut os_string: OsString = path.into();
-    os_string.push(".");
-    os_string.push(ext.as_ref());
-    os_string.into()
+/// Final cleanup of a (possibly truncated) path component.
+fn tidy_component(s: &str, rules: &FsRules) -> String {
+    // Trim leading/trailing whitespace, which can be problematic on some filesystems.
+    let s = s.trim();
+
+    // exfat normalises this and it confuses adb-sync and other tooling
+    let s = MULTI_DOT_RE.replace_all(s, ".");
+
+    // Disallow leading dots to prevent creating hidden files/directories.
+    // Disallow trailing dots as they are invalid on Windows.
+    let mut s = s.trim_matches('.').trim().to_string();
+
+    // Names like "CON" can't be created on Windows, even with an extension.
+    if rules.is_reserved(&s) {
+        s.push('_');
+    }
+
+    // If the component is now empty (e.g. it was just "."), use a placeholder.
+    if s.is_empty() {
+        "_".to_string()
+    } else {
+        s
+    }
+}
+
+/// Turns a rendered template into a path under `prefix` which is valid under `opts`, with `ext`
+/// appended to the file name. The file name is shortened further if needed to keep the whole path
+/// within the target's limit.
+///
+/// If `disambiguate` is set and the file name had to be truncated, a hash of the full name is
+/// appended so that tracks sharing a long common prefix don't end up at the same path.
+///
+/// Returns the path, and whether the file name was truncated.
+fn normalise_path(
+    rendered: &str,
+    prefix: &Path,
+    ext: &str,
+    opts: &PathOptions,
+    disambiguate: bool,
+) -> (PathBuf, bool) {
+    let rules = &opts.rules;
+    let parts: Vec<_> = Path::new(rendered)
+        .components()
+        .map(|c| replace_illegal(&c.as_os_str().to_string_lossy(), opts))
+        .collect();
+    let Some((file, dirs)) = parts.split_last() else {
+        return (prefix.join(format!("_.{ext}")), false);
+    };
+
+    let mut path = prefix.to_path_buf();
+    for dir in dirs {
+        path.push(tidy_component(
+            truncate(dir, opts.truncate_at_word, |p| opts.part_fits(p, "")),
+            rules,
+        ));
+    }
+
+    // The separator before the file name counts towards the path length, too.
+    let used = rules.len_unit.len(&path.to_string_lossy()) + 1;
+    let fits = |suffix: &str, p: &str| {
+        opts.part_fits(p, suffix)
+            && used + rules.len_unit.len(&format!("{p}{suffix}")) <= rules.max_path_len
+    };
+
+    let plain_suffix = format!(".{ext}");
+    let mut name = truncate(file, opts.truncate_at_word, |p| fits(&plain_suffix, p));
+    let truncated = name.len() < file.len();
+    let mut suffix = plain_suffix;
+    if truncated && disambiguate {
+        suffix = format!(" ~{}.{ext}", short_hash(file.as_bytes()));
+        name = truncate(file, opts.truncate_at_word, |p| fits(&suffix, p));
+    }
+
+    path.push(format!("{}{suffix}", tidy_component(name, rules)));
+    (path, truncated)
+}
+
+/// Per-run state and settings for renaming tracks.
+pub struct RenameContext {
+    pub opts: PathOptions,
+    /// Maps case-folded output directories to the first casing used for them during this run.
+    dir_case: Mutex<HashMap<String, PathBuf>>,
+    /// Maps (possibly case-folded) output files to the track which is being moved there.
+    claims: Mutex<HashMap<String, PathBuf>>,
+    /// Whether the output filesystem itself is case-insensitive, in which case renames that only
+    /// change case need special handling.
+    case_insensitive_fs: bool,
+    /// Output directories which tracks were moved into on a case-insensitive filesystem, whose
+    /// case is fixed once all moves are done.
+    case_fix_dirs: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl RenameContext {
+    pub fn new(opts: PathOptions, case_insensitive_fs: bool) -> Self {
+        Self {
+            opts,
+            dir_case: Mutex::new(HashMap::new()),
+            claims: Mutex::new(HashMap::new()),
+            case_insensitive_fs,
+            case_fix_dirs: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn fold(&self, s: &str) -> String {
+        if self.opts.rules.case_insensitive {
+            s.to_lowercase()
+        } else {
+            s.to_string()
+        }
+    }
+
+    /// On case-insensitive targets, paths which only differ by case refer to the same directory.
+    /// Make sure that directories are always spelled the same way within a run.
+    fn unify_dir_case(&self, to: PathBuf) -> PathBuf {
+        if !self.opts.rules.case_insensitive {
+            return to;
+        }
+        let Some(file_name) = to.file_name() else {
+            return to;
+        };
+
+        let mut map = self.dir_case.lock().expect("BUG: case map poisoned");
+        let mut unified = PathBuf::new();
+        let mut key = String::new();
+        for c in to.parent().into_iter().flat_map(Path::components) {
+            key.push_str(&self.fold(&c.as_os_str().to_string_lossy()));
+            key.push('/');
+            unified.clone_from(map.entry(key.clone()).or_insert_with(|| unified.join(c)));
+        }
+        unified.push(file_name);
+        unified
+    }
+
+    /// Works out the path under `output_path` for a track whose template rendered as `rendered`.
+    ///
+    /// Truncated file names always get a hash of the full name, rather than only when they
+    /// collide, since which of the colliding tracks kept the plain name would otherwise depend on
+    /// the order they happened to be processed in.
+    fn target_path(&self, rendered: &str, output_path: &Path, ext: &str) -> PathBuf {
+        let (path, _) = normalise_path(rendered, output_path, ext, &self.opts, true);
+        self.unify_dir_case(path)
+    }
+
+    /// Reserves `to` for the track at `from`, failing if another track in this run is already
+    /// going there, or if a different file already exists there. Paths differing only by case
+    /// are considered the same on case-insensitive targets.
+    fn claim(&self, from: &Path, to: &Path) -> Result<()> {
+        // Check the disk first, so that a failed claim doesn't leave an entry behind which would
+        // turn away the next track wanting this path.
+        self.check_collision_on_disk(from, to)?;
+        let key = self.fold(&to.to_string_lossy());
+        let mut claims = self.claims.lock().expect("BUG: claims map poisoned");
+        let source = claims.entry(key).or_insert_with(|| from.to_path_buf());
+        if source != from {
+            bail!(
+                "{} is also the destination for {}",
+                to.display(),
+                source.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the directories whose case may need fixing with `fix_dir_case`.
+    pub fn case_fix_dirs(&self) -> Vec<PathBuf> {
+        let dirs = self
+            .case_fix_dirs
+            .lock()
+            .expect("BUG: case fix dirs poisoned");
+        dirs.iter().cloned().collect()
+    }
+
+    /// On case-insensitive filesystems, existing directories won't change case just because we
+    /// moved a file into them under a differently cased name, so rename any directories between
+    /// `base` and `dir` to match the casing of `dir`.
+    ///
+    /// This renames directories other tracks may be moving into, so it must only be called once
+    /// all moves are done.
+    pub fn fix_dir_case(&self, base: &Path, dir: &Path) -> Result<()> {
+        let Ok(rel) = dir.strip_prefix(base) else {
+            return Ok(());
+        };
+        let mut cur = base.to_path_buf();
+        for c in rel.components() {
+            let wanted = c.as_os_str();
+            if let Some(existing) = find_case_variant(&cur, wanted) {
+                rename_case_only(&cur.join(existing), &cur.join(wanted))?;
+            }
+            cur.push(wanted);
+        }
+        Ok(())
+    }
+
+    /// Moves the track at `from` to `to`, taking care of case-only changes to the file name on
+    /// case-insensitive filesystems. The case of directories is fixed later by `fix_dir_case`.
+    fn move_track(&self, from: &Path, to: &Path) -> Result<()> {
+        if !self.case_insensitive_fs {
+            return rename_creating_dirs(from, to);
+        }
+
+        if let Some(dir) = to.parent() {
+            let mut dirs = self
+                .case_fix_dirs
+                .lock()
+                .expect("BUG: case fix dirs poisoned");
+            dirs.insert(dir.to_path_buf());
+        }
+        let from_lower = from.to_string_lossy().to_lowercase();
+        if from_lower != to.to_string_lossy().to_lowercase() {
+            return rename_creating_dirs(from, to);
+        }
+
+        // Same file, so only fix the case of its name here. If only the directories changed case,
+        // there's nothing to do until `fix_dir_case`.
+        let renamed = from.with_file_name(to.file_name().context("Target has no file name")?);
+        if renamed != from {
+            rename_case_only(from, &renamed)?;
+        }
+        Ok(())
+    }
+
+    /// Refuse to clobber an existing file, including one which only differs by case if the
+    /// target is case-insensitive.
+    fn check_collision_on_disk(&self, from: &Path, to: &Path) -> Result<()> {
+        let (Some(parent), Some(name)) = (to.parent(), to.file_name()) else {
+            return Ok(());
+        };
+        let Ok(entries) = fs::read_dir(parent) else {
+            return Ok(());
+        };
+        let name = self.fold(&name.to_string_lossy());
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if self.fold(&entry.file_name().to_string_lossy()) == name
+                && path != from
+                && !is_same_file(&path, from)
+            {
+                bail!(
+                    "{} would collide with existing file {}",
+                    to.display(),
+                    path.display()
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 pub fn rename_track(
     track: &Track,
     fp: &FormatPieces<Track>,
     output_path: &Path,
+    ctx: &RenameContext,
     dry_run: bool,
 ) -> Result<Option<PathBuf>> {
-    let mut new_path = output_path.to_path_buf();
-    let partial = normalise_dirs(fp.render(track)?);
-    new_path.push(partial);
-
-    // We might have truncated and have a dot elsewhere, so we can't use set_extension
-    new_path = add_extension(
-        new_path,
-        track
-            .path
-            .extension()
-            .context("ext required in walkbuilder, but missing")?,
-    );
+    let rendered = fp.render(track)?;
+    let ext = track
+        .path
+        .extension()
+        .context("ext required in walkbuilder, but missing")?
+        .to_string_lossy();
 
+    let new_path = ctx.target_path(&rendered, output_path, &ext);
     if new_path == track.path {
         return Ok(None);
     }
+    ctx.claim(&track.path, &new_path)?;
+
+    let path_len = ctx.opts.rules.len_unit.len(&new_path.to_string_lossy());
+    if path_len > ctx.opts.rules.max_path_len {
+        bail!(
+            "{} is too long for the target filesystem ({} > {})",
+            new_path.display(),
+            path_len,
+            ctx.opts.rules.max_path_len
+        );
+    }
 
     if !dry_run {
-        rename_creating_dirs(&track.path, &new_path)?;
+        ctx.move_track(&track.path, &new_path)?;
     }
 
     Ok(Some(new_path))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::profile::FsProfile;
+
+    fn opts(profile: FsProfile) -> PathOptions {
+        PathOptions {
+            rules: profile.rules(),
+            transliterate: false,
+            max_part_len: DEFAULT_MAX_PART_LEN,
+            part_len_unit: LenUnit::Chars,
+            part_len_includes_ext: false,
+            truncate_at_word: false,
+        }
+    }
+
+    fn normalise(given: &str, opts: &PathOptions) -> PathBuf {
+        normalise_path(given, Path::new(""), "mp3", opts, false).0
+    }
+
+    #[test]
+    fn test_normalise_path_legacy() {
+        let given = "AC:DC/..Who? Me/CON";
+        let expected = PathBuf::from("AC_DC/Who_ Me/CON.mp3");
+        assert_eq!(normalise(given, &opts(FsProfile::Legacy)), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_windows() {
+        let given = "AC:DC/..Who? Me/CON";
+        let expected = PathBuf::from("AC_DC/Who_ Me/CON_.mp3");
+        assert_eq!(normalise(given, &opts(FsProfile::Windows)), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_posix_keeps_windows_illegal() {
+        let given = "AC:DC/Who? Me/CON";
+        let expected = PathBuf::from("AC:DC/Who? Me/CON.mp3");
+        assert_eq!(normalise(given, &opts(FsProfile::Posix)), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_ascii() {
+        let given = "Björk/Vespertine";
+        let expected = PathBuf::from("Bj_rk/Vespertine.mp3");
+        assert_eq!(normalise(given, &opts(FsProfile::Ascii)), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_transliterate() {
+        let opts = PathOptions {
+            transliterate: true,
+            ..opts(FsProfile::Ascii)
+        };
+        let given = "Björk/Кино/コンビニ";
+        let expected = PathBuf::from("Bjork/Kino/konbini.mp3");
+        assert_eq!(normalise(given, &opts), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_truncates_excluding_ext() {
+        let opts = PathOptions {
+            max_part_len: 20,
+            ..opts(FsProfile::Posix)
+        };
+        let given = "A Rather Long Album Name/01 A Rather Long Title";
+        let expected = PathBuf::from("A Rather Long Album/01 A Rather Long Tit.mp3");
+        assert_eq!(normalise(given, &opts), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_truncates_at_word_boundary_including_ext() {
+        let opts = PathOptions {
+            max_part_len: 20,
+            part_len_includes_ext: true,
+            truncate_at_word: true,
+            ..opts(FsProfile::Posix)
+        };
+        let given = "A Rather Long Album Name/01 A Rather Long Title";
+        let expected = PathBuf::from("A Rather Long Album/01 A Rather.mp3");
+        assert_eq!(normalise(given, &opts), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_bytes() {
+        let opts = PathOptions {
+            max_part_len: 10,
+            part_len_unit: LenUnit::Bytes,
+            part_len_includes_ext: true,
+            ..opts(FsProfile::Posix)
+        };
+        // Each of these is 3 bytes in UTF-8, so only two fit alongside ".mp3".
+        let given = "宇宙コンビニ";
+        let expected = PathBuf::from("宇宙.mp3");
+        assert_eq!(normalise(given, &opts), expected);
+    }
+
+    #[test]
+    fn test_normalise_path_total_length() {
+        let opts = PathOptions {
+            rules: FsRules {
+                max_path_len: 20,
+                ..FsProfile::Posix.rules()
+            },
+            truncate_at_word: true,
+            ..opts(FsProfile::Posix)
+        };
+        let (path, truncated) =
+            normalise_path("01 Some Title", Path::new("/music"), "mp3", &opts, false);
+        assert_eq!(path, PathBuf::from("/music/01 Some.mp3"));
+        assert!(truncated);
+    }
+
+    #[test]
+    fn test_normalise_path_disambiguate() {
+        let opts = PathOptions {
+            max_part_len: 24,
+            part_len_includes_ext: true,
+            ..opts(FsProfile::Posix)
+        };
+        let (first, _) = normalise_path("01 Intro Part One", Path::new(""), "mp3", &opts, true);
+        let (second, _) = normalise_path("01 Intro Part Two", Path::new(""), "mp3", &opts, true);
+        assert_ne!(first, second);
+        assert!(first.to_string_lossy().ends_with(".mp3"));
+        assert!(first.to_string_lossy().len() <= 24);
+    }
+
+    #[test]
+    fn test_target_path_truncated_order_independent() {
+        let opts = PathOptions {
+            max_part_len: 12,
+            ..opts(FsProfile::Posix)
+        };
+        let tracks = [
+            (Path::new("a.mp3"), "01 Intro Part One"),
+            (Path::new("b.mp3"), "01 Intro Part Two"),
+        ];
+        let claim_all = |order: &[usize]| {
+            let ctx = RenameContext::new(opts, false);
+            let mut targets = std::collections::BTreeMap::new();
+            for &i in order {
+                let (from, rendered) = tracks[i];
+                let to = ctx.target_path(rendered, Path::new("out"), "mp3");
+                ctx.claim(from, &to).unwrap();
+                targets.insert(from, to);
+            }
+            targets
+        };
+        assert_eq!(claim_all(&[0, 1]), claim_all(&[1, 0]));
+    }
+
+    #[test]
+    fn test_unify_dir_case() {
+        let ctx = RenameContext::new(opts(FsProfile::Fat32), false);
+        let first = ctx.unify_dir_case(PathBuf::from("out/The Beatles/Help/01 Help.mp3"));
+        let second = ctx.unify_dir_case(PathBuf::from("out/the beatles/Help/02 Yesterday.mp3"));
+        assert_eq!(first, PathBuf::from("out/The Beatles/Help/01 Help.mp3"));
+        assert_eq!(
+            second,
+            PathBuf::from("out/The Beatles/Help/02 Yesterday.mp3")
+        );
+    }
+
+    #[test]
+    fn test_claim_case_insensitive() {
+        let ctx = RenameContext::new(opts(FsProfile::Fat32), false);
+        let to = Path::new("out/The Beatles/Help/01 Help.mp3");
+        assert!(ctx.claim(Path::new("a.mp3"), to).is_ok());
+        assert!(ctx.claim(Path::new("a.mp3"), to).is_ok());
+        assert!(ctx
+            .claim(
+                Path::new("b.mp3"),
+                Path::new("out/The Beatles/Help/01 HELP.mp3")
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_claim_same_target() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let to = base.join("01 Help.mp3");
+        let existing = base.join("01 help.mp3");
+        fs::write(&existing, b"").unwrap();
+
+        // A failed claim mustn't stop another track from claiming the path later.
+        let ctx = RenameContext::new(opts(FsProfile::Fat32), false);
+        assert!(ctx.claim(Path::new("a.mp3"), &to).is_err());
+        fs::remove_file(&existing).unwrap();
+        assert!(ctx.claim(Path::new("b.mp3"), &to).is_ok());
+        assert!(ctx.claim(Path::new("a.mp3"), &to).is_err());
+        assert!(ctx.claim(Path::new("b.mp3"), &to).is_ok());
+    }
+
+    #[test]
+    #[cfg(target_family = "unix")]
+    fn test_claim_case_only_dir_change() {
+        // A symlink stands in for a case-insensitive filesystem, where "the beatles" and "The
+        // Beatles" are the same directory.
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        fs::create_dir_all(base.join("The Beatles")).unwrap();
+        std::os::unix::fs::symlink("The Beatles", base.join("the beatles")).unwrap();
+        let from = base.join("the beatles/01 help.mp3");
+        fs::write(&from, b"").unwrap();
+
+        let ctx = RenameContext::new(opts(FsProfile::Fat32), true);
+        assert!(ctx
+            .claim(&from, &base.join("The Beatles/01 Help.mp3"))
+            .is_ok());
+        assert!(ctx
+            .claim(
+                Path::new("other.mp3"),
+                &base.join("The Beatles/01 HELP.mp3")
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_move_track_case_only() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let from = base.join("the beatles/help/01 help.mp3");
+        let to = base.join("The Beatles/Help/01 Help.mp3");
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"").unwrap();
+
+        let ctx = RenameContext::new(opts(FsProfile::Exfat), true);
+        ctx.move_track(&from, &to).unwrap();
+        for dir in ctx.case_fix_dirs() {
+            ctx.fix_dir_case(base, &dir).unwrap();
+        }
+
+        let names = |dir: &Path| -> Vec<OsString> {
+            fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect()
+        };
+        assert_eq!(names(base), vec![OsString::from("The Beatles")]);
+        assert_eq!(
+            names(&base.join("The Beatles")),
+            vec![OsString::from("Help")]
+        );
+        assert_eq!(
+            names(to.parent().unwrap()),
+            vec![OsString::from("01 Help.mp3")]
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use crate::atomic;
+use crate::track::date;
+use crate::track::writer::locate_id3v2_at;
+use anyhow::Result;
+use clap::ValueEnum;
+use id3::{Tag, TagLike};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+const ID3V1_LEN: u64 = 128;
+/// The "TAG+" extended ID3v1 block, which sits directly before a regular ID3v1 tag.
+const ID3V1_EXT_LEN: u64 = 227;
+const APE_FOOTER_LEN: u64 = 32;
+const APE_HAS_HEADER: u32 = 1 << 31;
+
+/// What to do with tags in a file other than its leading ID3v2 tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StrayTagMode {
+    /// Leave them alone
+    Keep,
+    /// Remove them
+    Strip,
+    /// Rewrite any ID3v1 tag to match the ID3v2 tag, and remove everything else
+    Sync,
+}
+
+/// Tags found in a file other than its leading ID3v2 tag, along with where the audio lives.
+#[derive(Debug, Default)]
+pub struct StrayTags {
+    /// Further ID3v2 tags directly after the first one, as left behind by some broken taggers.
+    pub extra_id3v2: Vec<Range<u64>>,
+    pub ape: Option<Range<u64>>,
+    pub id3v1: Option<Range<u64>>,
+    /// Everything other than the tags.
+    pub audio: Range<u64>,
+}
+
+fn has_magic_at(file: &mut fs::File, offset: u64, magic: &[u8]) -> Result<bool> {
+    let mut buf = vec![0; magic.len()];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf == magic)
+}
+
+pub fn read_range(file: &mut fs::File, range: Range<u64>) -> Result<Vec<u8>> {
+    let mut buf = vec![0; usize::try_from(range.end - range.start)?];
+    file.seek(SeekFrom::Start(range.start))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Finds the APEv2 tag ending at `end`, if there is one.
+fn locate_ape(file: &mut fs::File, audio_start: u64, end: u64) -> Result<Option<Range<u64>>> {
+    if end < audio_start + APE_FOOTER_LEN {
+        return Ok(None);
+    }
+    let footer = read_range(file, end - APE_FOOTER_LEN..end)?;
+    if &footer[..8] != b"APETAGEX" {
+        return Ok(None);
+    }
+
+    // The size covers the items and footer, but not the optional header.
+    let size = u64::from(u32::from_le_bytes(footer[12..16].try_into()?));
+    let flags = u32::from_le_bytes(footer[20..24].try_into()?);
+    let header_len = if flags & APE_HAS_HEADER == 0 {
+        0
+    } else {
+        APE_FOOTER_LEN
+    };
+    Ok(end
+        .checked_sub(size + header_len)
+        .filter(|&start| start >= audio_start)
+        .map(|start| start..end))
+}
+
+/// Finds all of the tags in `file` other than the leading ID3v2 tag.
+pub fn scan(file: &mut fs::File) -> Result<StrayTags> {
+    let len = file.metadata()?.len();
+
+    let mut extra_id3v2 = Vec::new();
+    let mut audio_start = 0;
+    if let Some(region) = locate_id3v2_at(file, 0)? {
+        audio_start = region.padded_end;
+        while let Some(region) = locate_id3v2_at(file, audio_start)? {
+            extra_id3v2.push(audio_start..region.padded_end);
+            audio_start = region.padded_end;
+        }
+    }
+    // Broken files can claim tags larger than the file itself.
+    let audio_start = audio_start.min(len);
+
+    let mut audio_end = len;
+    let mut id3v1 = None;
+    if audio_end >= audio_start + ID3V1_LEN && has_magic_at(file, audio_end - ID3V1_LEN, b"TAG")? {
+        let mut start = audio_end - ID3V1_LEN;
+        if start >= audio_start + ID3V1_EXT_LEN
+            && has_magic_at(file, start - ID3V1_EXT_LEN, b"TAG+")?
+        {
+            start -= ID3V1_EXT_LEN;
+        }
+        id3v1 = Some(start..audio_end);
+        audio_end = start;
+    }
+
+    let ape = locate_ape(file, audio_start, audio_end)?;
+    if let Some(ape) = &ape {
+        audio_end = ape.start;
+    }
+
+    Ok(StrayTags {
+        extra_id3v2,
+        ape,
+        id3v1,
+        audio: audio_start..audio_end,
+    })
+}
+
+/// Converts `s` to Latin-1, which is all that ID3v1 supports, approximating anything outside of
+/// it with ASCII.
+fn encode_latin1(s: &str) -> Vec<u8> {
+    s.chars()
+        .flat_map(|c| match u8::try_from(u32::from(c)) {
+            Ok(b) => vec![b],
+            Err(_) => deunicode::deunicode_char(c)
+                .unwrap_or("?")
+                .bytes()
+                .collect(),
+        })
+        .collect()
+}
+
+fn fill_field(field: &mut [u8], value: &str) {
+    let value = encode_latin1(value);
+    let len = value.len().min(field.len());
+    field.fill(0);
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Builds an ID3v1.1 tag matching `tag`, starting from the existing ID3v1 tag `old`. Fields which
+/// `tag` has no value for are kept from `old`, as are the comment and genre, since they can't be
+/// faithfully derived from an ID3v2 tag.
+fn make_id3v1(tag: &Tag, old: &[u8]) -> Vec<u8> {
+    let mut v1 = old.to_vec();
+    let fields = [
+        (3..33, tag.title().map(String::from)),
+        (33..63, tag.artist().map(String::from)),
+        (63..93, tag.album().map(String::from)),
+        (93..97, date::year(tag).map(|year| format!("{year:04}"))),
+    ];
+    for (range, value) in fields {
+        if let Some(value) = value {
+            fill_field(&mut v1[range], &value);
+        }
+    }
+    // The track number takes the last two bytes of the comment, turning this into ID3v1.1.
+    if let Some(track) = tag.track().and_then(|t| u8::try_from(t).ok()) {
+        v1[125] = 0;
+        v1[126] = track;
+    }
+    v1
+}
+
+/// The changes needed to bring a file's stray tags in line with a `StrayTagMode`.
+pub struct StrayFix {
+    tags: StrayTags,
+    /// The ID3v1 tag to write in place of the existing one, if any.
+    id3v1: Option<Vec<u8>>,
+    /// A description of each stray tag which will be changed, for reporting.
+    pub changes: Vec<&'static str>,
+}
+
+impl StrayFix {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Applies the fix to the file at `path`. Only the leading ID3v2 tag is kept in place, so this
+    /// must happen before it's rewritten.
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        atomic::modify_via_temp(path, |tmp| {
+            let data = fs::read(tmp)?;
+            let lead_end = self
+                .tags
+                .extra_id3v2
+                .first()
+                .map_or(self.tags.audio.start, |r| r.start);
+            let audio =
+                usize::try_from(self.tags.audio.start)?..usize::try_from(self.tags.audio.end)?;
+
+            let mut out = Vec::with_capacity(data.len());
+            out.extend_from_slice(&data[..usize::try_from(lead_end)?]);
+            out.extend_from_slice(&data[audio]);
+            if let Some(id3v1) = &self.id3v1 {
+                out.extend_from_slice(id3v1);
+            }
+            Ok(fs::write(tmp, out)?)
+        })
+    }
+}
+
+/// Works out what needs to change about the stray tags in the file at `path` for `mode`, given
+/// that the file's ID3v2 tag will become `tag`.
+pub fn check(path: &Path, tag: &Tag, mode: StrayTagMode) -> Result<StrayFix> {
+    let mut fix = StrayFix {
+        tags: StrayTags::default(),
+        id3v1: None,
+        changes: Vec::new(),
+    };
+    if mode == StrayTagMode::Keep {
+        return Ok(fix);
+    }
+    fix.tags = scan(&mut fs::File::open(path)?)?;
+
+    if !fix.tags.extra_id3v2.is_empty() {
+        fix.changes.push("duplicate ID3v2");
+    }
+    if fix.tags.ape.is_some() {
+        fix.changes.push("APEv2");
+    }
+    if let Some(range) = fix.tags.id3v1.clone() {
+        if mode == StrayTagMode::Sync {
+            let old = read_range(&mut fs::File::open(path)?, range)?;
+            let new = make_id3v1(tag, &old[old.len() - ID3V1_LEN as usize..]);
+            if old != new {
+                fix.changes.push("ID3v1 (synced)");
+            }
+            fix.id3v1 = Some(new);
+        } else {
+            fix.changes.push("ID3v1");
+        }
+    }
+    Ok(fix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id3::{Encoder, Version};
+
+    const AUDIO: &[u8] = b"\xff\xfb\x90\x64not really audio";
+
+    fn make_file(dir: &Path, tag: &Tag) -> std::path::PathBuf {
+        let path = dir.join("track.mp3");
+        let mut data = Vec::new();
+        for _ in 0..2 {
+            Encoder::new()
+                .version(Version::Id3v24)
+                .padding(16)
+                .encode(tag, &mut data)
+                .unwrap();
+        }
+        data.extend_from_slice(AUDIO);
+
+        let mut ape_footer = b"APETAGEX".to_vec();
+        ape_footer.extend_from_slice(&2000u32.to_le_bytes());
+        ape_footer.extend_from_slice(&32u32.to_le_bytes());
+        ape_footer.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&ape_footer);
+
+        let mut id3v1 = vec![0; ID3V1_LEN as usize];
+        id3v1[..3].copy_from_slice(b"TAG");
+        fill_field(&mut id3v1[3..33], "Stale title");
+        id3v1[127] = 17;
+        data.extend_from_slice(&id3v1);
+
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut tag = Tag::new();
+        tag.set_title("Title");
+        let tmp = tempfile::tempdir().unwrap();
+        let path = make_file(tmp.path(), &tag);
+        let strays = scan(&mut fs::File::open(&path).unwrap()).unwrap();
+
+        assert_eq!(strays.extra_id3v2.len(), 1);
+        assert!(strays.ape.is_some());
+        assert!(strays.id3v1.is_some());
+        let data = fs::read(&path).unwrap();
+        let audio = strays.audio.start as usize..strays.audio.end as usize;
+        assert_eq!(&data[audio], AUDIO);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut tag = Tag::new();
+        tag.set_title("Título");
+        tag.set_artist("Кино");
+        tag.set_track(3);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = make_file(tmp.path(), &tag);
+
+        let fix = check(&path, &tag, StrayTagMode::Sync).unwrap();
+        assert_eq!(fix.changes, ["duplicate ID3v2", "APEv2", "ID3v1 (synced)"]);
+        fix.apply(&path).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let (rest, id3v1) = data.split_at(data.len() - ID3V1_LEN as usize);
+        assert!(rest.ends_with(AUDIO));
+        assert_eq!(&id3v1[3..9], b"T\xedtulo");
+        assert_eq!(&id3v1[33..37], b"Kino");
+        assert_eq!(id3v1[126], 3);
+        assert_eq!(id3v1[127], 17);
+        assert_eq!(Tag::read_from_path(&path).unwrap().title(), Some("Título"));
+        assert!(check(&path, &tag, StrayTagMode::Sync).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_make_id3v1_keeps_fields_missing_from_id3v2() {
+        let mut old = vec![0; ID3V1_LEN as usize];
+        old[..3].copy_from_slice(b"TAG");
+        fill_field(&mut old[3..33], "A Much Longer Old Title");
+        fill_field(&mut old[63..93], "Old Album");
+        fill_field(&mut old[93..97], "1999");
+        fill_field(&mut old[97..127], "A comment");
+        let mut tag = Tag::new();
+        tag.set_title("New Title");
+
+        let v1 = make_id3v1(&tag, &old);
+        assert_eq!(&v1[3..12], b"New Title");
+        assert!(v1[12..33].iter().all(|&b| b == 0));
+        assert_eq!(&v1[63..93], &old[63..93]);
+        assert_eq!(&v1[93..97], b"1999");
+        assert_eq!(&v1[97..127], &old[97..127]);
+    }
+}
@@ -0,0 +1,374 @@
+use clap::ValueEnum;
+use id3::frame::{Content, InvolvedPeopleList, Unknown};
+use id3::{Frame, Tag, TagLike, Timestamp, Version};
+
+/// The ID3v2 version to write tags as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Id3Version {
+    /// ID3v2.3, for older players and car head units
+    #[value(name = "2.3")]
+    V23,
+    /// ID3v2.4
+    #[value(name = "2.4")]
+    V24,
+}
+
+impl From<Id3Version> for Version {
+    fn from(v: Id3Version) -> Self {
+        match v {
+            Id3Version::V23 => Self::Id3v23,
+            Id3Version::V24 => Self::Id3v24,
+        }
+    }
+}
+
+/// Returns the version to write `tag` as: `requested` if there is one, otherwise the version it
+/// already has. ID3v2.2 can't be written, so those tags become ID3v2.4.
+pub fn target_version(requested: Option<Version>, tag: &Tag) -> Version {
+    match (requested, tag.version()) {
+        (Some(version), _) => version,
+        (None, Version::Id3v23) => Version::Id3v23,
+        (None, _) => Version::Id3v24,
+    }
+}
+
+/// Frames which exist in ID3v2.3 but were dropped in ID3v2.4, mostly in favour of TDRC and TDOR.
+/// The X* sort order frames weren't standard, but are what ID3v2.3 taggers write instead of TSO*.
+const V23_ONLY_FRAMES: &[&str] = &[
+    "TYER", "TDAT", "TIME", "TORY", "TRDA", "TSIZ", "IPLS", "XSOP", "XSOA", "XSOT",
+];
+/// Frames which were introduced in ID3v2.4. Those without an ID3v2.3 equivalent, like the mood
+/// (TMOO) and release time (TDRL), are dropped when converting to ID3v2.3.
+const V24_ONLY_FRAMES: &[&str] = &[
+    "TDRC", "TDOR", "TIPL", "TMCL", "TSOP", "TSOA", "TSOT", "TDRL", "TDEN", "TDTG", "TMOO", "TPRO",
+    "TSST",
+];
+/// ID3v2.4 sort order frames, and their ID3v2.3 equivalents.
+const SORT_FRAMES: &[(&str, &str)] = &[("TSOP", "XSOP"), ("TSOA", "XSOA"), ("TSOT", "XSOT")];
+
+/// Renames a frame while keeping its content, since the id3 crate writes frame IDs verbatim
+/// regardless of version.
+fn rename_frame(tag: &mut Tag, from: &str, to: &str) {
+    for frame in tag.remove(from) {
+        tag.add_frame(Frame::with_content(to, frame.content().clone()));
+    }
+}
+
+/// Encodes `text` as the content of an ID3v2.3 X* sort order frame, which the id3 crate can only
+/// write as raw data since it isn't a standard text frame. This uses ISO-8859-1 where possible,
+/// and UTF-16 otherwise.
+fn encode_x_text(text: &str) -> Vec<u8> {
+    if text.chars().all(|c| u32::from(c) < 0x100) {
+        let mut data = vec![0];
+        data.extend(text.chars().map(|c| u32::from(c) as u8));
+        data
+    } else {
+        let mut data = vec![1, 0xff, 0xfe];
+        data.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        data
+    }
+}
+
+/// Decodes the content of an X* sort order frame, which the id3 crate reads as raw data.
+fn decode_x_text(data: &[u8]) -> Option<String> {
+    let (&encoding, data) = data.split_first()?;
+    let utf16 = |data: &[u8], big_endian: bool| {
+        let units: Vec<_> = data
+            .chunks_exact(2)
+            .map(|b| {
+                if big_endian {
+                    u16::from_be_bytes([b[0], b[1]])
+                } else {
+                    u16::from_le_bytes([b[0], b[1]])
+                }
+            })
+            .collect();
+        String::from_utf16(&units).ok()
+    };
+    let text = match (encoding, data) {
+        (0, _) => data.iter().map(|&b| char::from(b)).collect(),
+        (1, [0xff, 0xfe, rest @ ..]) => utf16(rest, false)?,
+        (1, [0xfe, 0xff, rest @ ..]) | (2, rest) => utf16(rest, true)?,
+        (3, _) => String::from_utf8(data.to_vec()).ok()?,
+        _ => return None,
+    };
+    Some(text.trim_end_matches('\0').to_owned())
+}
+
+/// Moves an ID3v2.4 sort order frame to its ID3v2.3 X* equivalent.
+fn sort_frame_to_v23(tag: &mut Tag, from: &str, to: &str) {
+    for frame in tag.remove(from) {
+        if let Some(text) = frame.content().text() {
+            let data = encode_x_text(text);
+            let version = Version::Id3v23;
+            tag.add_frame(Frame::with_content(
+                to,
+                Content::Unknown(Unknown { data, version }),
+            ));
+        }
+    }
+}
+
+/// Moves an ID3v2.3 X* sort order frame to its ID3v2.4 equivalent.
+fn sort_frame_to_v24(tag: &mut Tag, from: &str, to: &str) {
+    for frame in tag.remove(from) {
+        let text = match frame.content() {
+            Content::Text(text) => Some(text.clone()),
+            Content::Unknown(unknown) => decode_x_text(&unknown.data),
+            _ => None,
+        };
+        if let Some(text) = text {
+            tag.set_text(to, text);
+        }
+    }
+}
+
+/// Merges the involved people lists in the `from` frames into a single `to` frame, since ID3v2.3
+/// only has IPLS where ID3v2.4 has both TIPL (involved people) and TMCL (musician credits).
+fn merge_people_lists(tag: &mut Tag, from: &[&str], to: &str) {
+    let mut items = Vec::new();
+    for id in from {
+        for frame in tag.remove(id) {
+            if let Some(list) = frame.content().involved_people_list() {
+                items.extend(list.items.iter().cloned());
+            }
+        }
+    }
+    if !items.is_empty() {
+        let list = Content::InvolvedPeopleList(InvolvedPeopleList { items });
+        tag.add_frame(Frame::with_content(to, list));
+    }
+}
+
+fn parse_two_pairs(text: &str) -> Option<(u8, u8)> {
+    if text.len() != 4 || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((text[..2].parse().ok()?, text[2..].parse().ok()?))
+}
+
+/// Parses the DDMM value of an ID3v2.3 TDAT frame into a day and month, if it's a valid date.
+pub fn parse_tdat(text: &str) -> Option<(u8, u8)> {
+    parse_two_pairs(text).filter(|(day, month)| (1..=31).contains(day) && (1..=12).contains(month))
+}
+
+/// Parses the HHMM value of an ID3v2.3 TIME frame into an hour and minute, if it's a valid time.
+pub fn parse_time(text: &str) -> Option<(u8, u8)> {
+    parse_two_pairs(text).filter(|&(hour, minute)| hour <= 23 && minute <= 59)
+}
+
+/// Builds a TDRC style timestamp from the ID3v2.3 TYER, TDAT (DDMM), and TIME (HHMM) frames.
+fn v23_recording_time(tag: &Tag) -> Option<Timestamp> {
+    let mut ts = Timestamp {
+        year: tag.year()?,
+        month: None,
+        day: None,
+        hour: None,
+        minute: None,
+        second: None,
+    };
+    if let Some((day, month)) = tag
+        .get("TDAT")
+        .and_then(|f| parse_tdat(f.content().text()?))
+    {
+        ts.day = Some(day);
+        ts.month = Some(month);
+        if let Some((hour, minute)) = tag
+            .get("TIME")
+            .and_then(|f| parse_time(f.content().text()?))
+        {
+            ts.hour = Some(hour);
+            ts.minute = Some(minute);
+        }
+    }
+    Some(ts)
+}
+
+fn to_v24(tag: &mut Tag) {
+    if tag.date_recorded().is_none() {
+        if let Some(ts) = v23_recording_time(tag) {
+            tag.set_date_recorded(ts);
+        }
+    }
+    if tag.original_date_released().is_none() {
+        if let Some(year) = tag
+            .get("TORY")
+            .and_then(|f| f.content().text()?.parse().ok())
+        {
+            tag.set_original_date_released(Timestamp {
+                year,
+                month: None,
+                day: None,
+                hour: None,
+                minute: None,
+                second: None,
+            });
+        }
+    }
+    if tag.get("TIPL").is_none() {
+        rename_frame(tag, "IPLS", "TIPL");
+    }
+    for (v24, v23) in SORT_FRAMES {
+        if tag.get(v24).is_none() {
+            sort_frame_to_v24(tag, v23, v24);
+        }
+    }
+    for id in V23_ONLY_FRAMES {
+        tag.remove(id);
+    }
+}
+
+fn to_v23(tag: &mut Tag) {
+    if let Some(ts) = tag.date_recorded() {
+        tag.set_year(ts.year);
+        tag.remove("TDAT");
+        tag.remove("TIME");
+        if let (Some(month), Some(day)) = (ts.month, ts.day) {
+            tag.set_text("TDAT", format!("{day:02}{month:02}"));
+            if let (Some(hour), Some(minute)) = (ts.hour, ts.minute) {
+                tag.set_text("TIME", format!("{hour:02}{minute:02}"));
+            }
+        }
+    }
+    if let Some(ts) = tag.original_date_released() {
+        tag.set_text("TORY", format!("{:04}", ts.year));
+    }
+    if tag.get("IPLS").is_none() {
+        merge_people_lists(tag, &["TIPL", "TMCL"], "IPLS");
+    }
+    for (v24, v23) in SORT_FRAMES {
+        if tag.get(v23).is_none() {
+            sort_frame_to_v23(tag, v24, v23);
+        }
+    }
+    for id in V24_ONLY_FRAMES {
+        tag.remove(id);
+    }
+}
+
+/// Converts frames in `tag` which only exist in one ID3v2 version to their equivalents in
+/// `version`. Multi-value text separators are handled by the id3 crate when encoding.
+pub fn convert_frames(tag: &mut Tag, version: Version) {
+    match version {
+        Version::Id3v24 => to_v24(tag),
+        Version::Id3v23 => to_v23(tag),
+        Version::Id3v22 => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id3::frame::InvolvedPeopleListItem;
+
+    #[test]
+    fn test_target_version() {
+        let v23 = Tag::with_version(Version::Id3v23);
+        let v22 = Tag::with_version(Version::Id3v22);
+        assert_eq!(target_version(None, &v23), Version::Id3v23);
+        assert_eq!(target_version(None, &v22), Version::Id3v24);
+        assert_eq!(target_version(Some(Version::Id3v24), &v23), Version::Id3v24);
+    }
+
+    #[test]
+    fn test_convert_frames_to_v23() {
+        let mut tag = Tag::with_version(Version::Id3v24);
+        tag.set_text("TDRC", "2011-03-04T12:30");
+        tag.set_text("TDOR", "1999");
+        convert_frames(&mut tag, Version::Id3v23);
+
+        assert_eq!(tag.year(), Some(2011));
+        assert_eq!(
+            tag.get("TDAT").and_then(|f| f.content().text()),
+            Some("0403")
+        );
+        assert_eq!(
+            tag.get("TIME").and_then(|f| f.content().text()),
+            Some("1230")
+        );
+        assert_eq!(
+            tag.get("TORY").and_then(|f| f.content().text()),
+            Some("1999")
+        );
+        assert!(tag.get("TDRC").is_none());
+        assert!(tag.get("TDOR").is_none());
+    }
+
+    #[test]
+    fn test_convert_frames_to_v24() {
+        let mut tag = Tag::with_version(Version::Id3v23);
+        tag.set_text("TYER", "2011");
+        tag.set_text("TDAT", "0403");
+        tag.set_text("TORY", "1999");
+        convert_frames(&mut tag, Version::Id3v24);
+
+        assert_eq!(
+            tag.date_recorded().map(|ts| ts.to_string()),
+            Some("2011-03-04".to_owned())
+        );
+        assert_eq!(
+            tag.original_date_released().map(|ts| ts.to_string()),
+            Some("1999".to_owned())
+        );
+        assert!(tag.get("TYER").is_none());
+        assert!(tag.get("TDAT").is_none());
+        assert!(tag.get("TORY").is_none());
+    }
+
+    #[test]
+    fn test_convert_frames_round_trip() {
+        let people = |items: &[(&str, &str)]| {
+            let items = items
+                .iter()
+                .map(|(involvement, involvee)| InvolvedPeopleListItem {
+                    involvement: (*involvement).to_owned(),
+                    involvee: (*involvee).to_owned(),
+                })
+                .collect();
+            Content::InvolvedPeopleList(InvolvedPeopleList { items })
+        };
+        let mut tag = Tag::with_version(Version::Id3v24);
+        tag.set_text("TDRC", "2011-03-04");
+        tag.set_text("TSOP", "Beatles, The");
+        tag.set_text("TSOA", "Abbey Road ☺");
+        tag.set_text("TSOT", "Sømething");
+        tag.set_text("TMOO", "Happy");
+        tag.add_frame(Frame::with_content(
+            "TIPL",
+            people(&[("producer", "George Martin")]),
+        ));
+        tag.add_frame(Frame::with_content(
+            "TMCL",
+            people(&[("guitar", "George Harrison")]),
+        ));
+
+        convert_frames(&mut tag, Version::Id3v23);
+        let mut data = Vec::new();
+        tag.write_to(&mut data, Version::Id3v23).unwrap();
+        let mut tag = Tag::read_from2(std::io::Cursor::new(data)).unwrap();
+        assert!(tag.get("TMOO").is_none());
+        convert_frames(&mut tag, Version::Id3v24);
+
+        assert_eq!(
+            tag.date_recorded().map(|ts| ts.to_string()),
+            Some("2011-03-04".to_owned())
+        );
+        for (id, value) in [
+            ("TSOP", "Beatles, The"),
+            ("TSOA", "Abbey Road ☺"),
+            ("TSOT", "Sømething"),
+        ] {
+            assert_eq!(tag.get(id).and_then(|f| f.content().text()), Some(value));
+        }
+        assert_eq!(
+            tag.get("TIPL").map(|f| f.content().clone()),
+            Some(people(&[
+                ("producer", "George Martin"),
+                ("guitar", "George Harrison")
+            ]))
+        );
+        assert!(tag.get("TMCL").is_none());
+        assert!(tag.get("TMOO").is_none());
+        assert!(tag.frames().all(|f| !V23_ONLY_FRAMES.contains(&f.id())));
+    }
+}
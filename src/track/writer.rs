@@ -0,0 +1,147 @@
+use crate::atomic;
+use anyhow::Result;
+use id3::{Encoder, Tag, Version};
+use std::fs;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const ID3V2_HEADER_LEN: u64 = 10;
+const ID3V2_FOOTER_FLAG: u8 = 0x10;
+
+/// Where an ID3v2 tag lives in a file. All offsets are from the start of the file.
+pub struct Id3v2Region {
+    /// The end of the tag including any padding after it, which is where the audio starts.
+    pub padded_end: u64,
+}
+
+/// Finds the leading ID3v2 tag in `file`, or `None` if the file doesn't start with one.
+pub fn locate_id3v2(file: &mut fs::File) -> Result<Option<Id3v2Region>> {
+    locate_id3v2_at(file, 0)
+}
+
+/// Finds an ID3v2 tag starting at `start` in `file`, or `None` if there isn't one there.
+pub fn locate_id3v2_at(file: &mut fs::File, start: u64) -> Result<Option<Id3v2Region>> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut header = [0; ID3V2_HEADER_LEN as usize];
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(None);
+    }
+
+    let size = header[6..]
+        .iter()
+        .fold(0, |acc, &b| (acc << 7) | u64::from(b & 0x7f));
+    let mut tag_end = start + ID3V2_HEADER_LEN + size;
+    if header[5] & ID3V2_FOOTER_FLAG != 0 {
+        tag_end += ID3V2_HEADER_LEN;
+    }
+
+    file.seek(SeekFrom::Start(tag_end))?;
+    let padding = BufReader::new(&*file)
+        .bytes()
+        .take_while(|b| matches!(b, Ok(0)))
+        .count();
+    Ok(Some(Id3v2Region {
+        padded_end: tag_end + padding as u64,
+    }))
+}
+
+/// Rewrites the existing tag in place if the new one fits exactly into the space used by the old
+/// tag and its padding, so the file size doesn't change and the audio is never moved. Returns
+/// false if this isn't possible.
+fn write_in_place(tag: &Tag, path: &Path, version: Version) -> Result<bool> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let Some(region) = locate_id3v2(&mut file)? else {
+        return Ok(false);
+    };
+    // Some broken files claim a tag larger than the file itself, don't make that any worse.
+    if region.padded_end >= file.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut buf = Vec::new();
+    Encoder::new().version(version).encode(tag, &mut buf)?;
+    let available = region.padded_end as usize;
+    let Some(padding) = available.checked_sub(buf.len()) else {
+        return Ok(false);
+    };
+
+    buf.clear();
+    Encoder::new()
+        .version(version)
+        .padding(padding)
+        .encode(tag, &mut buf)?;
+    if buf.len() != available {
+        return Ok(false);
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    Ok(true)
+}
+
+/// Writes `tag` to the file at `path` such that a crash or interruption can't leave a truncated
+/// or half-written file behind. If the tag fits in the existing padding it's written in place,
+/// otherwise the whole file is rewritten to a temporary file and renamed over the original, which
+/// fails for files with other hard links or whose owner we can't keep.
+pub fn write_tag(tag: &Tag, path: &Path, version: Version) -> Result<()> {
+    if write_in_place(tag, path, version)? {
+        return Ok(());
+    }
+    atomic::modify_via_temp(path, |tmp| Ok(tag.write_to_path(tmp, version)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id3::TagLike;
+
+    const AUDIO: &[u8] = b"\xff\xfb\x90\x64not really audio";
+
+    fn make_file(dir: &Path, padding: usize) -> std::path::PathBuf {
+        let path = dir.join("track.mp3");
+        let mut tag = Tag::new();
+        tag.set_title("Old");
+        let mut data = Vec::new();
+        Encoder::new()
+            .padding(padding)
+            .encode(&tag, &mut data)
+            .unwrap();
+        data.extend_from_slice(AUDIO);
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn check_written(path: &Path) {
+        let tag = Tag::read_from_path(path).unwrap();
+        assert_eq!(tag.title(), Some("A much longer new title"));
+        assert!(fs::read(path).unwrap().ends_with(AUDIO));
+    }
+
+    fn new_tag() -> Tag {
+        let mut tag = Tag::new();
+        tag.set_title("A much longer new title");
+        tag
+    }
+
+    #[test]
+    fn test_write_tag_in_place_with_padding() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = make_file(tmp.path(), 1024);
+        let len = fs::metadata(&path).unwrap().len();
+        write_tag(&new_tag(), &path, Version::Id3v24).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        check_written(&path);
+    }
+
+    #[test]
+    fn test_write_tag_via_temp_without_padding() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = make_file(tmp.path(), 0);
+        let len = fs::metadata(&path).unwrap().len();
+        write_tag(&new_tag(), &path, Version::Id3v24).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > len);
+        check_written(&path);
+        assert!(!atomic::temp_path_for(&path).exists());
+    }
+}